@@ -0,0 +1,81 @@
+//! Registration time of a dense scan against `Config::max_num_threads`, the
+//! correspondence search running on the rayon pool.
+//!
+//! `cargo bench --bench association_threads --features rayon`
+
+use nalgebra as na;
+use simple_icp::{
+    config::Config,
+    icp_pipeline::{register_scan_with_workspace, IcpWorkspace},
+    point3d::{transform_points, Point3d},
+    voxel_hash_map::VoxelHashMap,
+};
+use std::{hint::black_box, time::Instant};
+
+/// Three orthogonal 20 m walls sampled every 10 cm.
+fn corner_scene() -> Vec<Point3d> {
+    let mut points = Vec::new();
+    for i in 0..200 {
+        for j in 0..200 {
+            let (a, b) = (0.1 * i as f32, 0.1 * j as f32);
+            points.push(Point3d::new(0.0, a, b, 1.0));
+            points.push(Point3d::new(a, 0.0, b, 1.0));
+            points.push(Point3d::new(a, b, 0.0, 1.0));
+        }
+    }
+    points
+}
+
+/// Median time of `repeats` runs of `f`, in milliseconds.
+fn median_ms(repeats: usize, mut f: impl FnMut()) -> f64 {
+    let mut times: Vec<f64> = (0..repeats)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed().as_secs_f64() * 1e3
+        })
+        .collect();
+    times.sort_by(f64::total_cmp);
+    times[repeats / 2]
+}
+
+fn main() {
+    let scene = corner_scene();
+    let mut map = VoxelHashMap::default_values();
+    map.update_with_pose(
+        &scene,
+        &na::Isometry3::identity(),
+        &na::Isometry3::identity(),
+    );
+    let truth = na::Isometry3::new(na::Vector3::new(0.2, 0.1, 0.05), na::Vector3::z() * 0.02);
+    let scan = transform_points(&scene, &truth.inverse());
+    let guess = na::Isometry3::identity();
+    println!(
+        "{} scan points, {} cores",
+        scan.len(),
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    );
+
+    let mut single_thread_ms = None;
+    for max_num_threads in [1, 2, 4, 8, 0] {
+        let config = Config {
+            max_num_threads,
+            ..Config::default_values()
+        };
+        let mut workspace = IcpWorkspace::new();
+        let ms = median_ms(3, || {
+            black_box(register_scan_with_workspace(
+                &scan,
+                &map,
+                &config,
+                &guess,
+                &mut workspace,
+            ));
+        });
+        let baseline = *single_thread_ms.get_or_insert(ms);
+        println!(
+            "max_num_threads {max_num_threads}: {ms:7.1} ms/scan ({:.2}x)",
+            baseline / ms
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Heap allocations and time per registered scan, with a fresh `IcpWorkspace` per
+//! scan (what `register_scan` does) and with one workspace reused across scans.
+//!
+//! `cargo bench --bench icp_workspace`
+
+use nalgebra as na;
+use simple_icp::{
+    config::Config,
+    icp_pipeline::{register_scan, register_scan_with_workspace, IcpWorkspace},
+    point3d::{transform_points, Point3d},
+    voxel_hash_map::VoxelHashMap,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+/// Counts the allocations going through the system allocator.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Three orthogonal 20 m walls sampled every 10 cm.
+fn corner_scene() -> Vec<Point3d> {
+    let mut points = Vec::new();
+    for i in 0..200 {
+        for j in 0..200 {
+            let (a, b) = (0.1 * i as f32, 0.1 * j as f32);
+            points.push(Point3d::new(0.0, a, b, 1.0));
+            points.push(Point3d::new(a, 0.0, b, 1.0));
+            points.push(Point3d::new(a, b, 0.0, 1.0));
+        }
+    }
+    points
+}
+
+const NUM_SCANS: usize = 20;
+
+/// Allocations and milliseconds per scan of `register` over `NUM_SCANS` scans.
+fn measure(mut register: impl FnMut()) -> (f64, f64) {
+    // warm up, lets a reused workspace grow to the scan size
+    register();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..NUM_SCANS {
+        register();
+    }
+    let elapsed = start.elapsed().as_secs_f64() * 1e3;
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+    (
+        allocations as f64 / NUM_SCANS as f64,
+        elapsed / NUM_SCANS as f64,
+    )
+}
+
+fn main() {
+    let scene = corner_scene();
+    let mut map = VoxelHashMap::default_values();
+    map.update_with_pose(
+        &scene,
+        &na::Isometry3::identity(),
+        &na::Isometry3::identity(),
+    );
+    let truth = na::Isometry3::new(na::Vector3::new(0.2, 0.1, 0.05), na::Vector3::z() * 0.02);
+    let scan: Vec<Point3d> = transform_points(&scene, &truth.inverse())
+        .into_iter()
+        .step_by(4)
+        .collect();
+    let config = Config::default_values();
+    let guess = na::Isometry3::identity();
+    println!("{} scan points, {} map points", scan.len(), map.map_len());
+
+    let (allocations, ms) = measure(|| {
+        black_box(register_scan(&scan, &map, &config, &guess));
+    });
+    println!("fresh workspace : {allocations:8.1} allocations/scan, {ms:6.2} ms/scan");
+
+    let mut workspace = IcpWorkspace::new();
+    let (allocations, ms) = measure(|| {
+        black_box(register_scan_with_workspace(
+            &scan,
+            &map,
+            &config,
+            &guess,
+            &mut workspace,
+        ));
+    });
+    println!("reused workspace: {allocations:8.1} allocations/scan, {ms:6.2} ms/scan");
+}
@@ -0,0 +1,60 @@
+//! Batched `transform_points` against the per-point isometry closure it replaced,
+//! on a 100k point scan.
+//!
+//! `cargo bench --bench transform_points`
+
+use nalgebra as na;
+use simple_icp::point3d::{transform_points, Point3d, PointLike};
+use std::{hint::black_box, time::Instant};
+
+/// Median time of `repeats` runs of `f`, in milliseconds.
+fn median_ms(repeats: usize, mut f: impl FnMut()) -> f64 {
+    let mut times: Vec<f64> = (0..repeats)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed().as_secs_f64() * 1e3
+        })
+        .collect();
+    times.sort_by(f64::total_cmp);
+    times[repeats / 2]
+}
+
+fn main() {
+    let scan: Vec<Point3d> = (0..100_000)
+        .map(|i| {
+            let angle = i as f32 * 0.001;
+            let range = 5.0 + (i % 97) as f32 * 0.3;
+            Point3d::new(
+                range * angle.cos(),
+                range * angle.sin(),
+                (i % 64) as f32 * 0.05,
+                1.0,
+            )
+        })
+        .collect();
+    let transform = na::Isometry3::new(
+        na::Vector3::new(1.0, -2.0, 0.5),
+        na::Vector3::new(0.1, -0.2, 0.7),
+    );
+
+    let per_point = median_ms(51, || {
+        let transformed: Vec<Point3d> = black_box(&scan)
+            .iter()
+            .map(|pt| {
+                let p = transform * na::Point3::from(pt.position());
+                pt.with_position(&p.coords)
+            })
+            .collect();
+        black_box(transformed);
+    });
+    let batched = median_ms(51, || {
+        black_box(transform_points(black_box(&scan), &transform));
+    });
+    println!("{} points", scan.len());
+    println!("per-point isometry: {per_point:6.3} ms");
+    println!(
+        "transform_points  : {batched:6.3} ms ({:.2}x)",
+        per_point / batched
+    );
+}
@@ -0,0 +1,88 @@
+//! Bucket distribution and lookup latency of `VoxelBuildHasher` against the std
+//! SipHash `RandomState`, on the dense keys of a LiDAR map slab.
+//!
+//! `cargo bench --bench voxel_hash`
+
+use simple_icp::voxel_hash_map::{Voxel, VoxelBuildHasher};
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    hint::black_box,
+    time::Instant,
+};
+
+/// Keys of a 128 x 128 x 8 voxel slab around the origin.
+fn slab_keys() -> Vec<Voxel> {
+    let mut keys = Vec::new();
+    for x in -64..64 {
+        for y in -64..64 {
+            for z in -2..6 {
+                keys.push(Voxel::new(x, y, z));
+            }
+        }
+    }
+    keys
+}
+
+/// Occupied fraction and largest load of the buckets of a table sized like
+/// hashbrown's (power of two, 7/8 load factor), indexed by the low hash bits.
+fn bucket_distribution(keys: &[Voxel], build: &impl BuildHasher) -> (f64, usize) {
+    let num_buckets = (keys.len() * 8 / 7).next_power_of_two();
+    let mut loads = vec![0usize; num_buckets];
+    for key in keys {
+        loads[build.hash_one(key) as usize & (num_buckets - 1)] += 1;
+    }
+    let occupied = loads.iter().filter(|load| **load > 0).count();
+    (
+        occupied as f64 / keys.len() as f64,
+        loads.into_iter().max().unwrap_or(0),
+    )
+}
+
+/// Median time of `repeats` runs of `f`, in nanoseconds per operation.
+fn median_ns(repeats: usize, ops: usize, mut f: impl FnMut()) -> f64 {
+    let mut times: Vec<f64> = (0..repeats)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed().as_nanos() as f64 / ops as f64
+        })
+        .collect();
+    times.sort_by(f64::total_cmp);
+    times[repeats / 2]
+}
+
+fn bench<S: BuildHasher + Default>(name: &str, keys: &[Voxel]) {
+    let (occupied, max_load) = bucket_distribution(keys, &S::default());
+    let map: HashMap<Voxel, usize, S> = keys.iter().enumerate().map(|(i, k)| (*k, i)).collect();
+    let hits = median_ns(21, keys.len(), || {
+        for key in keys {
+            black_box(map.get(black_box(key)));
+        }
+    });
+    // 3x3x3 neighborhoods, as in the correspondence search
+    let neighborhood_ops = keys.len() / 8 * 27;
+    let neighborhoods = median_ns(21, neighborhood_ops, || {
+        for key in keys.iter().step_by(8) {
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        black_box(map.get(&(key + Voxel::new(dx, dy, dz))));
+                    }
+                }
+            }
+        }
+    });
+    println!(
+        "{name:>12}: {:5.1}% keys in distinct buckets, max bucket load {max_load:2}, \
+         {hits:5.1} ns/hit, {neighborhoods:5.1} ns/neighborhood lookup",
+        100.0 * occupied
+    );
+}
+
+fn main() {
+    let keys = slab_keys();
+    println!("{} voxel keys", keys.len());
+    bench::<RandomState>("SipHash", &keys);
+    bench::<VoxelBuildHasher>("VoxelHasher", &keys);
+}
@@ -1,5 +1,11 @@
 use nalgebra as na;
 
+/// Correspondence threshold adapting to the observed motion model error (KISS-ICP).
+///
+/// Feed it the deviation between the predicted and the registered pose of every
+/// scan with [`AdaptiveThreshold::update_model_deviation`], and read the threshold
+/// (sigma) for the next registration with [`AdaptiveThreshold::compute_threshold`].
+#[derive(Debug, Clone)]
 pub struct AdaptiveThreshold {
     // configurable parameters
     min_motion_threshold: f64,
@@ -10,6 +16,9 @@ pub struct AdaptiveThreshold {
     num_samples: f64,
 }
 impl AdaptiveThreshold {
+    /// `init_thres` is the threshold until enough motion has been observed, deviations
+    /// below `min_motion_threshold` (in meters) are ignored and `max_range` converts
+    /// rotation errors into the displacement of the farthest point.
     pub fn new(init_thres: f64, min_motion_threshold: f64, max_range: f64) -> AdaptiveThreshold {
         AdaptiveThreshold {
             min_motion_threshold,
@@ -18,14 +27,20 @@ impl AdaptiveThreshold {
             num_samples: 1.0,
         }
     }
+    /// Current threshold, the RMS of the model errors seen so far.
     pub fn compute_threshold(&self) -> f64 {
         (self.model_sse / self.num_samples).sqrt()
     }
+    /// Accounts for `current_deviation`, the transform from the predicted to the
+    /// registered pose.
     pub fn update_model_deviation(&mut self, current_deviation: &na::Isometry3<f64>) {
         let theta = current_deviation.rotation.angle();
         let delta_rot = 2.0 * self.max_range * (theta / 2.0).sin();
         let delta_trans = current_deviation.translation.vector.norm();
-        let model_error = delta_rot + delta_trans;
+        self.update_model_error(delta_rot + delta_trans);
+    }
+    /// Accounts for a model error already expressed in meters.
+    pub fn update_model_error(&mut self, model_error: f64) {
         if model_error > self.min_motion_threshold {
             self.model_sse += model_error * model_error;
             self.num_samples += 1.0;
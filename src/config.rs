@@ -1,12 +1,104 @@
+use nalgebra as na;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+use crate::error::ConfigError;
+use crate::range_image::RangeImageParams;
+
+/// What to do with points of a scan stamped before the end of the previous scan
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampOverlapPolicy {
+    /// Register and insert every point
+    Keep,
+    /// Drop overlapping points before registration
+    Drop,
+    /// Use overlapping points for registration but don't insert them into the map
+    RegisterOnly,
+}
+
+/// How deskewing obtains the pose of each point
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeskewMode {
+    /// Interpolate the pose history, skip deskewing if it is invalid
+    Interpolate,
+    /// Interpolate, or extrapolate the velocity of the last two poses when the
+    /// history is too sparse or discontinuous
+    ConstantVelocityFallback,
+}
+
+/// Residual minimized by ICP
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationMode {
+    PointToPoint,
+    /// Residuals projected on surface normals, point-to-point where no normal is available
+    PointToPlane,
+}
+
+/// Normals driving the point-to-plane residual
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NormalSource {
+    /// Normals of the map around the matched point, standard point-to-plane
+    Target,
+    /// Normals of the scan around the source point
+    Source,
+    /// Sum of both, the symmetric objective
+    Both,
+}
+
+/// IRLS weighting of the correspondences by their residual
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RobustKernel {
+    /// Identity weights, plain least squares
+    None,
+    Huber,
+    /// Tukey bisquare, zero weight beyond the scale
+    Tukey,
+    Cauchy,
+    /// `k^2 / (k + r^2)^2` as in KISS-ICP
+    GemanMcClure,
+}
+
+impl RobustKernel {
+    /// IRLS weight of a correspondence with residual norm `residual` under `scale`.
+    pub fn weight(&self, residual: f64, scale: f64) -> f64 {
+        let square = |x: f64| x * x;
+        match self {
+            RobustKernel::None => 1.0,
+            RobustKernel::Huber if residual <= scale => 1.0,
+            RobustKernel::Huber => scale / residual,
+            RobustKernel::Tukey if residual <= scale => square(1.0 - square(residual / scale)),
+            RobustKernel::Tukey => 0.0,
+            RobustKernel::Cauchy => 1.0 / (1.0 + square(residual / scale)),
+            RobustKernel::GemanMcClure => square(scale) / square(scale + square(residual)),
+        }
+    }
+}
+
+/// How ICP finds the map point matching a scan point
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DataAssociation {
+    /// Nearest neighbor in the surrounding voxels
+    VoxelSearch,
+    /// Nearest neighbor around the projection in a range image of the map
+    RangeImage(RangeImageParams),
+}
+
+/// Missing fields take their value from [`Config::default_values`] when deserializing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
 pub struct Config {
     // map params
     pub voxel_size: f32,
     pub max_range: f32,
     pub min_range: f32,
+    /// Drop (0, 0, 0) points (invalid returns) regardless of `min_range`
+    pub drop_origin_points: bool,
     pub max_points_per_voxel: u16,
+    /// Minimum overlap and inlier ratio for a registered scan to be merged into the map
+    pub min_overlap_to_insert: f64,
+    /// Recenter the map on the sensor once it moves this far from the map origin,
+    /// keeping coordinates small on large traversals (None = fixed origin). Poses are
+    /// then relative to the map origin, see `IcpPipeline::global_pose`
+    pub recenter_distance: Option<f64>,
 
     // th parms
     pub min_motion_th: f64,
@@ -15,18 +107,75 @@ pub struct Config {
     // registration params
     pub max_num_iterations: u16,
     pub convergence_criterion: f64,
+    /// Separate convergence thresholds on the se3 update, (translation in meters,
+    /// rotation in radians), both must be met. Replaces `convergence_criterion`
+    /// on the combined norm when set
+    pub convergence_thresholds: Option<na::Vector2<f64>>,
+    /// Threads of the correspondence search with the `rayon` feature (0 = all cores)
     pub max_num_threads: u8,
+    /// Clamp of each se3 update, (max translation in meters, max rotation in radians)
+    pub max_step: Option<na::Vector2<f64>>,
+    /// Lower bound on the robust kernel weight of a correspondence, keeps the
+    /// Hessian full rank when most correspondences are outliers (0.0 = disabled)
+    pub min_weight: f64,
+    pub robust_kernel: RobustKernel,
+    /// Scale of the robust kernel (None = a third of the adaptive threshold)
+    pub robust_kernel_scale: Option<f64>,
+    /// Weight of the intensity difference in data association and residual weights,
+    /// in meters per intensity unit (0.0 = geometry only). Gating and stats still use
+    /// the geometric distance
+    pub intensity_weight: f64,
+    /// Weight of the photometric residual of colored points against the geometric one
+    /// (0.0 = geometry only)
+    pub color_weight: f64,
+    /// Reject correspondences whose intensities differ by more than this (None = disabled)
+    pub max_intensity_diff: Option<f32>,
+    /// Inlier ratio below which a registration is considered failed
+    pub min_inlier_ratio: f64,
+    /// Abort the registration when an iteration finds fewer correspondences, e.g. in
+    /// a featureless corridor, keeping the last pose (None = disabled)
+    pub min_correspondences: Option<usize>,
+    pub data_association: DataAssociation,
+    /// Voxels searched around a point for its closest map point along each axis, the
+    /// cost grows with the cube of the radius (1 = the surrounding 3x3x3 voxels)
+    pub search_voxel_radius: u8,
+    /// Upper bound on the radius (in voxels) of any neighborhood search of the map,
+    /// larger requests are clamped to it
+    pub max_adjacent_voxels: u8,
+    /// Point-to-plane usually converges in fewer iterations on structured scenes
+    pub registration_mode: RegistrationMode,
+    pub normal_source: NormalSource,
+
+    /// Reject scans whose valid point count differs from the running average by
+    /// more than this factor, either way (None = disabled). A jump lasting a few
+    /// scans becomes the new average
+    pub max_point_count_ratio: Option<f64>,
 
     // Motion compensation
     pub deskew: bool,
+    pub deskew_mode: DeskewMode,
+    /// Skip deskewing when the motion during the scan is below both thresholds
+    /// (in meters and radians)
+    pub deskew_min_translation: f64,
+    pub deskew_min_rotation: f64,
+    /// Timestamp offset (in seconds) per return number, applied before deskewing
+    /// (empty = all returns stamped at the firing time)
+    pub return_time_offsets: Vec<f64>,
 
     // Point aging (None = disabled, Some(seconds) = enabled)
     pub max_point_age_seconds: Option<f64>,
+    /// Age map points from their insertion instead of their capture time
+    pub age_from_insert: bool,
 
     /// Maximum allowed distance between consecutive poses for deskewing (in meters)
     pub max_distance_between_poses: f64,
     /// Maximum allowed angle between consecutive poses for deskewing (in radians)
     pub max_angle_between_poses: f64,
+    /// Handling of points stamped before the end of the previous scan
+    pub timestamp_overlap: TimestampOverlapPolicy,
+
+    /// Intensity blend factor for near-duplicate map points (None = disabled)
+    pub intensity_blend: Option<f32>,
 }
 impl Config {
     pub fn default_values() -> Config {
@@ -34,7 +183,10 @@ impl Config {
             voxel_size: 1.0,
             max_range: 100.0,
             min_range: 1.0,
+            drop_origin_points: false,
             max_points_per_voxel: 20,
+            min_overlap_to_insert: 0.0,
+            recenter_distance: None,
 
             // th parms
             min_motion_th: 0.1,
@@ -43,15 +195,384 @@ impl Config {
             // registration params
             max_num_iterations: 500,
             convergence_criterion: 0.0001,
+            convergence_thresholds: None,
             max_num_threads: 0,
+            max_step: None,
+            min_weight: 0.0,
+            robust_kernel: RobustKernel::GemanMcClure,
+            robust_kernel_scale: None,
+            intensity_weight: 0.0,
+            color_weight: 0.0,
+            max_intensity_diff: None,
+            min_inlier_ratio: 0.5,
+            min_correspondences: None,
+            data_association: DataAssociation::VoxelSearch,
+            search_voxel_radius: 1,
+            max_adjacent_voxels: 8,
+            registration_mode: RegistrationMode::PointToPoint,
+            normal_source: NormalSource::Target,
+
+            max_point_count_ratio: None,
 
             // Motion compensation
             deskew: false,
+            deskew_mode: DeskewMode::Interpolate,
+            deskew_min_translation: 0.0,
+            deskew_min_rotation: 0.0,
+            return_time_offsets: Vec::new(),
 
             // Point aging
             max_point_age_seconds: Some(30.0), // 30 seconds default
             max_distance_between_poses: 0.05,  // 5 centimeters
             max_angle_between_poses: std::f64::consts::PI / 18.0, // 10 degrees
+            age_from_insert: false,
+            timestamp_overlap: TimestampOverlapPolicy::Keep,
+
+            intensity_blend: None,
+        }
+    }
+}
+
+impl Config {
+    /// Checks the values that would corrupt the map or stall registration, listing
+    /// every offending field.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+        // written so NaN fails every check
+        let positive = |value: f64| value > 0.0;
+        let non_negative = |value: f64| value >= 0.0;
+        let unit_interval = |value: f64| (0.0..=1.0).contains(&value);
+        if !positive(self.voxel_size as f64) {
+            errors.push(format!("voxel_size must be > 0, got {}", self.voxel_size));
+        }
+        if !(non_negative(self.min_range as f64) && self.min_range < self.max_range) {
+            errors.push(format!(
+                "min_range and max_range must satisfy 0 <= min_range < max_range, got {} and {}",
+                self.min_range, self.max_range
+            ));
+        }
+        if self.max_points_per_voxel < 1 {
+            errors.push("max_points_per_voxel must be >= 1, got 0".to_string());
+        }
+        if self.search_voxel_radius < 1 {
+            errors.push("search_voxel_radius must be >= 1, got 0".to_string());
+        }
+        if self.search_voxel_radius > self.max_adjacent_voxels {
+            errors.push(format!(
+                "search_voxel_radius must be <= max_adjacent_voxels, got {} and {}",
+                self.search_voxel_radius, self.max_adjacent_voxels
+            ));
+        }
+        if !positive(self.convergence_criterion) {
+            errors.push(format!(
+                "convergence_criterion must be > 0, got {}",
+                self.convergence_criterion
+            ));
+        }
+        if let Some(thresholds) = self.convergence_thresholds {
+            if !(positive(thresholds.x) && positive(thresholds.y)) {
+                errors.push(format!(
+                    "convergence_thresholds must be > 0, got {} and {}",
+                    thresholds.x, thresholds.y
+                ));
+            }
+        }
+        if let Some(max_step) = self.max_step {
+            if !(positive(max_step.x) && positive(max_step.y)) {
+                errors.push(format!(
+                    "max_step must be > 0, got {} and {}",
+                    max_step.x, max_step.y
+                ));
+            }
+        }
+        if let Some(scale) = self.robust_kernel_scale {
+            if !positive(scale) {
+                errors.push(format!("robust_kernel_scale must be > 0, got {scale}"));
+            }
+        }
+        if let Some(offset) = self.return_time_offsets.iter().find(|o| !o.is_finite()) {
+            errors.push(format!("return_time_offsets must be finite, got {offset}"));
+        }
+        let thresholds = [
+            ("min_motion_th", self.min_motion_th),
+            (
+                "max_distance_between_poses",
+                self.max_distance_between_poses,
+            ),
+            ("max_angle_between_poses", self.max_angle_between_poses),
+            ("deskew_min_translation", self.deskew_min_translation),
+            ("deskew_min_rotation", self.deskew_min_rotation),
+        ];
+        for (field, value) in thresholds {
+            if !non_negative(value) {
+                errors.push(format!("{field} must be >= 0, got {value}"));
+            }
+        }
+        let weights = [
+            ("intensity_weight", self.intensity_weight),
+            ("color_weight", self.color_weight),
+        ];
+        for (field, value) in weights {
+            if !non_negative(value) {
+                errors.push(format!("{field} must be >= 0, got {value}"));
+            }
+        }
+        let fractions = [
+            ("min_weight", Some(self.min_weight)),
+            ("min_overlap_to_insert", Some(self.min_overlap_to_insert)),
+            ("intensity_blend", self.intensity_blend.map(f64::from)),
+        ];
+        for (field, value) in fractions {
+            if let Some(value) = value.filter(|value| !unit_interval(*value)) {
+                errors.push(format!("{field} must be in [0, 1], got {value}"));
+            }
+        }
+        if let DataAssociation::RangeImage(params) = self.data_association {
+            if params.width < 1 || params.height < 1 {
+                errors.push(format!(
+                    "range image width and height must be >= 1, got {} and {}",
+                    params.width, params.height
+                ));
+            }
+            let elevations = [params.min_elevation, params.max_elevation];
+            if !(elevations.iter().all(|e| e.is_finite()) && elevations[0] < elevations[1]) {
+                errors.push(format!(
+                    "range image elevations must be finite with min_elevation < max_elevation, got {} and {}",
+                    params.min_elevation, params.max_elevation
+                ));
+            }
+            if params.search_window >= params.width {
+                errors.push(format!(
+                    "range image search_window must be < width, got {} and {}",
+                    params.search_window, params.width
+                ));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(errors))
+        }
+    }
+}
+
+impl Config {
+    /// Whether an ICP update `dx` ([translation, rotation]) is small enough to stop.
+    pub fn converged(&self, dx: &na::Vector6<f64>) -> bool {
+        match self.convergence_thresholds {
+            Some(thresholds) => {
+                dx.fixed_rows::<3>(0).norm() < thresholds.x
+                    && dx.fixed_rows::<3>(3).norm() < thresholds.y
+            }
+            None => dx.norm() < self.convergence_criterion,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config::default_values()
+    }
+}
+
+#[cfg(feature = "toml")]
+impl Config {
+    /// TOML has no null, so an optional setting left out takes its default: aging
+    /// (`max_point_age_seconds`) can't be disabled from a TOML file, unlike YAML.
+    pub fn from_toml_path(path: impl AsRef<std::path::Path>) -> Result<Config, ConfigError> {
+        let config: Config = toml::from_str(&read_config(path.as_ref())?)
+            .map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(feature = "serde_yaml")]
+impl Config {
+    pub fn from_yaml_path(path: impl AsRef<std::path::Path>) -> Result<Config, ConfigError> {
+        let config: Config = serde_yaml::from_str(&read_config(path.as_ref())?)
+            .map_err(|e| ConfigError::Parse(e.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+#[cfg(any(feature = "toml", feature = "serde_yaml"))]
+fn read_config(path: &std::path::Path) -> Result<String, ConfigError> {
+    std::fs::read_to_string(path).map_err(|e| ConfigError::Read(format!("{}: {e}", path.display())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_fields(config: &Config) -> Vec<String> {
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(ConfigError::Invalid(errors)) => errors,
+            Err(e) => panic!("unexpected error {e}"),
         }
     }
+
+    #[test]
+    fn degenerate_range_image_is_rejected() {
+        let params = RangeImageParams {
+            width: 1024,
+            height: 64,
+            min_elevation: -0.4,
+            max_elevation: 0.4,
+            search_window: 2,
+        };
+        let with = |params| Config {
+            data_association: DataAssociation::RangeImage(params),
+            ..Config::default_values()
+        };
+        assert!(invalid_fields(&with(params)).is_empty());
+        for params in [
+            RangeImageParams { width: 0, ..params },
+            RangeImageParams {
+                height: 0,
+                ..params
+            },
+            RangeImageParams {
+                min_elevation: 0.4,
+                ..params
+            },
+            RangeImageParams {
+                search_window: 1024,
+                ..params
+            },
+        ] {
+            assert!(!invalid_fields(&with(params)).is_empty());
+        }
+    }
+
+    #[test]
+    fn search_radius_beyond_the_neighborhood_limit_is_rejected() {
+        let config = Config {
+            search_voxel_radius: 3,
+            max_adjacent_voxels: 2,
+            ..Config::default_values()
+        };
+        assert_eq!(invalid_fields(&config).len(), 1);
+    }
+
+    #[test]
+    fn non_positive_step_and_kernel_scale_are_rejected() {
+        let step = |x, y| Config {
+            max_step: Some(na::Vector2::new(x, y)),
+            ..Config::default_values()
+        };
+        assert!(invalid_fields(&step(0.5, 0.1)).is_empty());
+        assert_eq!(invalid_fields(&step(0.0, 0.1)).len(), 1);
+        assert_eq!(invalid_fields(&step(0.5, -0.1)).len(), 1);
+        assert_eq!(invalid_fields(&step(f64::NAN, 0.1)).len(), 1);
+        let scale = |scale| Config {
+            robust_kernel_scale: Some(scale),
+            ..Config::default_values()
+        };
+        assert!(invalid_fields(&scale(0.3)).is_empty());
+        assert_eq!(invalid_fields(&scale(0.0)).len(), 1);
+    }
+
+    #[test]
+    fn non_finite_return_time_offsets_are_rejected() {
+        let config = Config {
+            return_time_offsets: vec![0.0, f64::INFINITY],
+            ..Config::default_values()
+        };
+        assert_eq!(invalid_fields(&config).len(), 1);
+    }
+
+    #[test]
+    fn tukey_weight_vanishes_for_large_residuals() {
+        let scale = 0.5;
+        let weights: Vec<f64> = [0.0, 0.1, 0.25, 0.4, 0.49, 0.5, 2.0, 100.0]
+            .iter()
+            .map(|r| RobustKernel::Tukey.weight(*r, scale))
+            .collect();
+        assert_eq!(weights[0], 1.0);
+        assert!(weights.windows(2).all(|w| w[1] <= w[0]));
+        assert!(weights[4] < 1e-2);
+        assert!(weights[5..].iter().all(|w| *w == 0.0));
+        assert_eq!(RobustKernel::None.weight(100.0, scale), 1.0);
+    }
+
+    #[cfg(any(feature = "toml", feature = "serde_yaml"))]
+    fn tuned_config() -> Config {
+        Config {
+            voxel_size: 0.75,
+            max_step: Some(na::Vector2::new(0.5, 0.1)),
+            robust_kernel: RobustKernel::Tukey,
+            max_point_age_seconds: Some(10.0),
+            return_time_offsets: vec![0.0, 1e-6],
+            ..Config::default_values()
+        }
+    }
+
+    #[cfg(any(feature = "toml", feature = "serde_yaml"))]
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("simple_icp_{}_{name}", std::process::id()))
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_config_round_trip() {
+        let config = tuned_config();
+        let path = temp_path("config.toml");
+        std::fs::write(&path, toml::to_string(&config).unwrap()).unwrap();
+        let read = Config::from_toml_path(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), config);
+    }
+
+    #[cfg(feature = "serde_yaml")]
+    #[test]
+    fn yaml_config_round_trip() {
+        // unlike TOML, YAML can disable aging
+        let config = Config {
+            max_point_age_seconds: None,
+            ..tuned_config()
+        };
+        let path = temp_path("config.yaml");
+        std::fs::write(&path, serde_yaml::to_string(&config).unwrap()).unwrap();
+        let read = Config::from_yaml_path(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), config);
+    }
+
+    #[test]
+    fn weights_and_fractions_out_of_range_are_rejected() {
+        let config = Config {
+            intensity_weight: -0.1,
+            color_weight: f64::NAN,
+            min_weight: 1.5,
+            min_overlap_to_insert: -0.2,
+            intensity_blend: Some(2.0),
+            ..Config::default_values()
+        };
+        let fields: Vec<_> = invalid_fields(&config)
+            .iter()
+            .map(|error| error.split(' ').next().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                "intensity_weight",
+                "color_weight",
+                "min_weight",
+                "min_overlap_to_insert",
+                "intensity_blend"
+            ]
+        );
+        let bounds = Config {
+            intensity_weight: 0.0,
+            color_weight: 3.0,
+            min_weight: 1.0,
+            min_overlap_to_insert: 0.0,
+            intensity_blend: Some(1.0),
+            ..Config::default_values()
+        };
+        assert!(invalid_fields(&bounds).is_empty());
+    }
 }
@@ -1,69 +1,397 @@
-use std::time::Instant;
+use std::{
+    cmp::Ordering,
+    time::{Duration, Instant},
+};
 
-use nalgebra::Isometry3;
+use nalgebra::{Isometry3, Vector3};
 
-use crate::point3d::Point3d;
+use crate::{
+    config::DeskewMode,
+    error::DeskewError,
+    lie_group::{Exp, ExpAndTheta, Log},
+    point3d::{Point3d, PointLike},
+};
 
 use nalgebra::{Translation3, UnitQuaternion};
 
+/// What a successful deskew did to the scan.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeskewResult {
+    /// Points whose time is covered by the poses, others are left untouched
+    pub num_corrected: usize,
+    /// Largest displacement applied to a point (m)
+    pub max_correction: f64,
+}
+
 /// Deskews a point cloud by interpolating robot poses for every point
 /// and projecting them to the frame of the scan's end time.
 ///
 /// # Arguments
 /// * `points` - The mutable slice of points to deskew.
 /// * `poses` - A history of robot poses (Global Frame) sorted by time.
-pub fn deskew_scan(
-    points: &mut [Point3d],
+///
+/// Points are left untouched when an error is returned.
+pub fn deskew_scan<P: PointLike>(
+    points: &mut [P],
+    poses: &[(Instant, Isometry3<f64>)],
+    max_angle_between_poses: f64,
+    max_distance_between_poses: f64,
+) -> Result<DeskewResult, DeskewError> {
+    deskew_scan_with_mode(
+        points,
+        poses,
+        max_angle_between_poses,
+        max_distance_between_poses,
+        DeskewMode::Interpolate,
+    )
+}
+
+/// [`deskew_scan`] with a fallback for pose histories that can't be interpolated.
+pub fn deskew_scan_with_mode<P: PointLike>(
+    points: &mut [P],
     poses: &[(Instant, Isometry3<f64>)],
     max_angle_between_poses: f64,
     max_distance_between_poses: f64,
-) {
-    if points.is_empty()
-        || poses.is_empty()
-        || !validate_poses(poses, max_distance_between_poses, max_angle_between_poses)
-    {
-        eprintln!("Deskewing skipped due to insufficient data or invalid poses.");
-        return;
-    }
-    let scan_end_time = points
+    mode: DeskewMode,
+) -> Result<DeskewResult, DeskewError> {
+    let Some(scan_end_time) = points.iter().map(|p| p.timestamp()).max() else {
+        return Err(DeskewError::EmptyPoints);
+    };
+    let error = if poses.is_empty() {
+        DeskewError::EmptyPoses
+    } else if !validate_poses(poses, max_distance_between_poses, max_angle_between_poses) {
+        DeskewError::InvalidPoses
+    } else {
+        return deskew_points_to_time(points, poses, scan_end_time, |p| p.timestamp());
+    };
+    let extrapolated = match mode {
+        DeskewMode::Interpolate => None,
+        DeskewMode::ConstantVelocityFallback => {
+            let scan_start_time = points.iter().map(|p| p.timestamp()).min().unwrap();
+            constant_velocity_poses(poses, scan_start_time, scan_end_time)
+        }
+    };
+    match extrapolated {
+        Some(poses) => deskew_points_to_time(points, &poses, scan_end_time, |p| p.timestamp()),
+        None => Err(error),
+    }
+}
+
+/// Time stamp of a pose history, shared by the `Instant` and the Unix time (`f64`
+/// seconds) deskew paths.
+pub trait PoseTime: Copy + PartialOrd {
+    /// Seconds elapsed since `earlier`, zero if `earlier` is later.
+    fn seconds_since(&self, earlier: &Self) -> f64;
+}
+
+impl PoseTime for Instant {
+    fn seconds_since(&self, earlier: &Self) -> f64 {
+        self.saturating_duration_since(*earlier).as_secs_f64()
+    }
+}
+
+impl PoseTime for f64 {
+    fn seconds_since(&self, earlier: &Self) -> f64 {
+        (self - earlier).max(0.0)
+    }
+}
+
+/// [`deskew_scan`] with poses keyed by Unix time in seconds, e.g. from
+/// [`crate::io::read_tum_trajectory`]. `clock_anchor` is the Unix time of an
+/// `Instant`, used to convert the point timestamps to the pose timeline.
+pub fn deskew_scan_unix<P: PointLike>(
+    points: &mut [P],
+    poses: &[(f64, Isometry3<f64>)],
+    clock_anchor: (Instant, f64),
+    max_angle_between_poses: f64,
+    max_distance_between_poses: f64,
+) -> Result<DeskewResult, DeskewError> {
+    let (anchor_instant, anchor_unix) = clock_anchor;
+    let to_unix = |time: Instant| match time.checked_duration_since(anchor_instant) {
+        Some(elapsed) => anchor_unix + elapsed.as_secs_f64(),
+        None => anchor_unix - anchor_instant.duration_since(time).as_secs_f64(),
+    };
+    let Some(scan_end_time) = points.iter().map(|p| p.timestamp()).max() else {
+        return Err(DeskewError::EmptyPoints);
+    };
+    if poses.is_empty() {
+        return Err(DeskewError::EmptyPoses);
+    }
+    if !validate_poses(poses, max_distance_between_poses, max_angle_between_poses) {
+        return Err(DeskewError::InvalidPoses);
+    }
+    deskew_points_to_time(points, poses, to_unix(scan_end_time), |p| {
+        to_unix(p.timestamp())
+    })
+}
+
+/// Poses from `start` to `end` extrapolated with the velocity between the last two
+/// poses of increasing time. `None` without two such poses.
+fn constant_velocity_poses(
+    poses: &[(Instant, Isometry3<f64>)],
+    start: Instant,
+    end: Instant,
+) -> Option<Vec<(Instant, Isometry3<f64>)>> {
+    let (t1, pose1) = *poses.last()?;
+    let (t0, pose0) = *poses.iter().rev().find(|(t, _)| *t < t1)?;
+    // body frame twist per second
+    let velocity = (pose0.inverse() * pose1).log() / t1.duration_since(t0).as_secs_f64();
+    let pose_at = |time: Instant| {
+        let dt = if time >= t1 {
+            time.duration_since(t1).as_secs_f64()
+        } else {
+            -t1.duration_since(time).as_secs_f64()
+        };
+        pose1 * (velocity * dt).exp()
+    };
+    // knots close enough for the interpolation to follow the screw motion
+    let step = end.duration_since(start) / CONSTANT_VELOCITY_SEGMENTS;
+    Some(
+        (0..=CONSTANT_VELOCITY_SEGMENTS)
+            .map(|i| start + step * i)
+            .chain(std::iter::once(end))
+            .map(|time| (time, pose_at(time)))
+            .collect(),
+    )
+}
+
+const CONSTANT_VELOCITY_SEGMENTS: u32 = 16;
+
+/// An IMU reading: time, angular velocity (rad/s) and specific force (m/s^2, gravity
+/// included as output by the sensor), both in the body frame.
+pub type ImuSample = (Instant, Vector3<f64>, Vector3<f64>);
+
+/// Deskews a scan without a pose history by integrating IMU readings from
+/// `start_pose`, the world pose at the first point time, and `start_velocity`, the
+/// world frame velocity at that time. The IMU only measures acceleration, so the
+/// velocity is the integration constant it can't provide: take it from the odometry
+/// (e.g. the last two registered poses), or pass zero if unknown since rotation
+/// usually dominates over a scan. Readings are held until the next one and the first
+/// reading is used before it, so samples not spanning the scan are clamped to the
+/// nearest one.
+pub fn deskew_scan_imu<P: PointLike>(
+    points: &mut [P],
+    start_pose: &Isometry3<f64>,
+    start_velocity: &Vector3<f64>,
+    imu_samples: &[ImuSample],
+) -> Result<DeskewResult, DeskewError> {
+    let (Some(scan_start), Some(scan_end)) = (
+        points.iter().map(|p| p.timestamp()).min(),
+        points.iter().map(|p| p.timestamp()).max(),
+    ) else {
+        return Err(DeskewError::EmptyPoints);
+    };
+    if imu_samples.is_empty() {
+        return Err(DeskewError::EmptyImuSamples);
+    }
+    let gravity = Vector3::new(0.0, 0.0, -GRAVITY);
+    let sample_at = |time: Instant| {
+        let idx = imu_samples.partition_point(|(t, _, _)| *t <= time);
+        imu_samples[idx.saturating_sub(1)]
+    };
+    // pose at the scan start and at every reading during the scan
+    let mut knots: Vec<Instant> = imu_samples
         .iter()
-        .map(|p| p.global_timestamp)
-        .max()
-        .unwrap_or(points.last().unwrap().global_timestamp);
+        .map(|(t, _, _)| *t)
+        .filter(|t| *t > scan_start && *t < scan_end)
+        .collect();
+    knots.push(scan_end);
+
+    let mut poses = vec![(scan_start, *start_pose)];
+    let mut velocity = *start_velocity;
+    for time in knots {
+        let (t_prev, pose) = *poses.last().unwrap();
+        let (_, angular_velocity, specific_force) = sample_at(t_prev);
+        let dt = time.duration_since(t_prev).as_secs_f64();
+        let acceleration = pose.rotation * specific_force + gravity;
+        let translation = pose.translation.vector + velocity * dt + 0.5 * acceleration * dt * dt;
+        let rotation = pose.rotation * (angular_velocity * dt).exp_and_theta().0;
+        velocity += acceleration * dt;
+        poses.push((time, Isometry3::from_parts(translation.into(), rotation)));
+    }
 
-    let pose_at_scan_end = match interpolate_pose_at_time(poses, scan_end_time) {
+    deskew_points_to_time(points, &poses, scan_end, |p| p.timestamp())
+}
+
+/// Standard gravity (m/s^2), along -z of the world frame.
+const GRAVITY: f64 = 9.80665;
+
+/// Shifts the timestamp of every point by the offset of its return number (in
+/// seconds, may be negative), for multi-return sensors stamping every return of a
+/// beam with the firing time. Returns without a finite offset are left untouched.
+pub fn apply_return_time_offsets<P: PointLike>(points: &mut [P], offsets: &[f64]) {
+    for point in points.iter_mut() {
+        let Some(&offset) = offsets.get(point.return_number() as usize) else {
+            continue;
+        };
+        let Ok(delta) = Duration::try_from_secs_f64(offset.abs()) else {
+            continue;
+        };
+        let timestamp = if offset >= 0.0 {
+            point.timestamp().checked_add(delta)
+        } else {
+            point.timestamp().checked_sub(delta)
+        };
+        if let Some(timestamp) = timestamp {
+            *point = point.with_timestamp(timestamp);
+        }
+    }
+}
+
+/// Projects every point into the frame of the pose at `reference_time`.
+fn deskew_points_to_time<P: PointLike, T: PoseTime>(
+    points: &mut [P],
+    poses: &[(T, Isometry3<f64>)],
+    reference_time: T,
+    time_of: impl Fn(&P) -> T,
+) -> Result<DeskewResult, DeskewError> {
+    let pose_at_reference = match interpolate_pose_at_time(poses, reference_time) {
         Some(pose) => pose,
-        None => return,
+        None => return Err(DeskewError::InvalidPoses),
+    };
+    let pose_at_scan_end_inv = pose_at_reference.inverse();
+    let mut result = DeskewResult {
+        num_corrected: 0,
+        max_correction: 0.0,
     };
-    let pose_at_scan_end_inv = pose_at_scan_end.inverse();
 
     for point in points.iter_mut() {
-        if let Some(pose_at_point_time) = interpolate_pose_at_time(poses, point.global_timestamp) {
+        if let Some(pose_at_point_time) = interpolate_pose_at_time(poses, time_of(point)) {
             // P_world = Pose(t) * P_raw
             // P_corrected = Pose(end)^-1 * P_world
             // (Pose(end)^-1 * Pose(t)) * P_raw
 
             let correction_transform = pose_at_scan_end_inv * pose_at_point_time;
 
-            let original_point = point.to_na_point3_f64();
-            let corrected_point = correction_transform * original_point;
+            let original_point = point.position().into();
+            let corrected_point = correction_transform.transform_point(&original_point);
+
+            *point = point.with_position(&corrected_point.coords);
+            result.num_corrected += 1;
+            result.max_correction = result
+                .max_correction
+                .max((corrected_point - original_point).norm());
+        }
+    }
+    Ok(result)
+}
+
+/// Deskews a continuous point stream in fixed time windows instead of sensor frames.
+///
+/// Points and poses are pushed as they arrive. Once the pose history covers the
+/// end of the current window, the window's points are deskewed to the window end
+/// time and emitted, while later points are kept for the next window.
+pub struct StreamingDeskewer<P = Point3d> {
+    window: Duration,
+    max_angle_between_poses: f64,
+    max_distance_between_poses: f64,
+    window_start: Option<Instant>,
+    points: Vec<P>,
+    poses: Vec<(Instant, Isometry3<f64>)>,
+}
+
+impl<P: PointLike> StreamingDeskewer<P> {
+    pub fn new(
+        window: Duration,
+        max_angle_between_poses: f64,
+        max_distance_between_poses: f64,
+    ) -> Self {
+        StreamingDeskewer {
+            window,
+            max_angle_between_poses,
+            max_distance_between_poses,
+            window_start: None,
+            points: Vec::new(),
+            poses: Vec::new(),
+        }
+    }
+
+    /// Poses must be pushed in increasing time order, older ones are ignored.
+    pub fn add_pose(&mut self, time: Instant, pose: Isometry3<f64>) {
+        if self.poses.last().is_some_and(|(t, _)| time <= *t) {
+            return;
+        }
+        self.poses.push((time, pose));
+    }
+
+    pub fn add_points(&mut self, points: &[P]) {
+        if self.window_start.is_none() {
+            self.window_start = points.iter().map(|p| p.timestamp()).min();
+        }
+        self.points.extend_from_slice(points);
+    }
 
-            point.x = corrected_point.x as f32;
-            point.y = corrected_point.y as f32;
-            point.z = corrected_point.z as f32;
+    /// Returns the points of the oldest completed window with the outcome of their
+    /// deskewing (left raw on error), or `None` if no window is complete yet or the
+    /// poses are not covering it.
+    pub fn pop_window(&mut self) -> Option<(Vec<P>, Result<DeskewResult, DeskewError>)> {
+        let window_start = self.window_start?;
+        let window_end = window_start + self.window;
+        let (last_pose_time, _) = self.poses.last()?;
+        if *last_pose_time < window_end {
+            return None;
         }
+
+        let (mut window_points, tail): (Vec<P>, Vec<P>) =
+            self.points.iter().partition(|p| p.timestamp() < window_end);
+        self.points = tail;
+        self.window_start = Some(window_end);
+
+        // poses up to the first one at or after the window end bracket the window
+        let end_idx = self.poses.partition_point(|(t, _)| *t < window_end);
+        let result = if window_points.is_empty() {
+            Err(DeskewError::EmptyPoints)
+        } else if validate_poses(
+            &self.poses[..=end_idx],
+            self.max_distance_between_poses,
+            self.max_angle_between_poses,
+        ) {
+            deskew_points_to_time(&mut window_points, &self.poses, window_end, |p| {
+                p.timestamp()
+            })
+        } else {
+            Err(DeskewError::InvalidPoses)
+        };
+
+        // keep the last pose before the next window so it can still be interpolated
+        let keep_from = self
+            .poses
+            .partition_point(|(t, _)| *t <= window_end)
+            .saturating_sub(1);
+        self.poses.drain(..keep_from);
+        Some((window_points, result))
     }
 }
 
-fn validate_poses(
+/// Estimates whether the motion over the last `scan_duration` of the pose history is
+/// large enough for deskewing to matter, i.e. exceeds either threshold.
+pub fn deskew_needed(
     poses: &[(Instant, Isometry3<f64>)],
+    scan_duration: Duration,
+    translation_threshold: f64,
+    rotation_threshold: f64,
+) -> bool {
+    let Some((end_time, end_pose)) = poses.last() else {
+        return false;
+    };
+    let start_time = end_time.checked_sub(scan_duration).unwrap_or(poses[0].0);
+    let Some(start_pose) = interpolate_pose_at_time(poses, start_time) else {
+        return false;
+    };
+    let motion = start_pose.inverse() * end_pose;
+    motion.translation.vector.norm() > translation_threshold
+        || motion.rotation.angle() > rotation_threshold
+}
+
+fn validate_poses<T: PoseTime>(
+    poses: &[(T, Isometry3<f64>)],
     max_distance_between_poses: f64,
     max_angle_between_poses: f64,
 ) -> bool {
     for window in poses.windows(2) {
         let pose1 = &window[0];
         let pose2 = &window[1];
-        if pose2.0 <= pose1.0 {
+        if pose2.0.partial_cmp(&pose1.0) != Some(Ordering::Greater) {
             return false;
         }
         // if poses are too far apart that means a loop may have been closed or apriltag seen, or something to cause a discontinuity
@@ -83,9 +411,9 @@ fn validate_poses(
     true
 }
 
-fn interpolate_pose_at_time(
-    poses: &[(Instant, Isometry3<f64>)],
-    time: Instant,
+fn interpolate_pose_at_time<T: PoseTime>(
+    poses: &[(T, Isometry3<f64>)],
+    time: T,
 ) -> Option<Isometry3<f64>> {
     if let Some(first) = poses.first() {
         if time <= first.0 {
@@ -108,12 +436,12 @@ fn interpolate_pose_at_time(
     let (t_prev, pose_prev) = poses[idx - 1];
     let (t_next, pose_next) = poses[idx];
 
-    let total_duration = t_next.duration_since(t_prev).as_secs_f64();
+    let total_duration = t_next.seconds_since(&t_prev);
     if total_duration <= f64::EPSILON {
         return Some(pose_prev);
     }
 
-    let current_duration = time.duration_since(t_prev).as_secs_f64();
+    let current_duration = time.seconds_since(&t_prev);
     let alpha = current_duration / total_duration;
 
     let translation = Translation3::from(
@@ -127,3 +455,209 @@ fn interpolate_pose_at_time(
 
     Some(Isometry3::from_parts(translation, rotation))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Moving along x at 1 m/s and turning at 1 rad/s.
+    fn pose_at(seconds: f64) -> Isometry3<f64> {
+        Isometry3::new(Vector3::new(seconds, 0.0, 0.0), Vector3::z() * seconds)
+    }
+
+    #[test]
+    fn streaming_deskew_is_consistent_across_window_boundaries() {
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let mut deskewer = StreamingDeskewer::new(Duration::from_millis(100), 0.2, 0.05);
+        // the same raw point just before and just after the first window end, windows
+        // start at the first point
+        let raw = Vector3::new(5.0, 2.0, 1.0);
+        let times = [0, 99, 101];
+        let points: Vec<Point3d> = times
+            .iter()
+            .map(|ms| {
+                Point3d::new_with_timestamp(raw.x as f32, raw.y as f32, raw.z as f32, 1.0, at(*ms))
+            })
+            .collect();
+        deskewer.add_points(&points);
+        for ms in (0..=300).step_by(10) {
+            deskewer.add_pose(at(ms), pose_at(ms as f64 / 1000.0));
+        }
+
+        for window in [1, 2] {
+            let (window_points, result) = deskewer.pop_window().unwrap();
+            assert_eq!(result.unwrap().num_corrected, window_points.len());
+            let (point, point_ms) = match window {
+                1 => (window_points[1], times[1]),
+                _ => (window_points[0], times[2]),
+            };
+            // both windows put the point where the raw point was observed in the world
+            let window_end = pose_at(window as f64 * 0.1);
+            let world = window_end * nalgebra::Point3::from(point.position());
+            let expected = pose_at(point_ms as f64 / 1000.0) * nalgebra::Point3::from(raw);
+            assert!((world - expected).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn deskew_needed_only_for_fast_motion() {
+        let t0 = Instant::now();
+        let scan = Duration::from_millis(100);
+        let history = |pose: fn(f64) -> Isometry3<f64>| -> Vec<_> {
+            (0..=10)
+                .map(|i| (t0 + Duration::from_millis(20 * i), pose(0.02 * i as f64)))
+                .collect()
+        };
+        let stationary = history(|_| Isometry3::identity());
+        assert!(!deskew_needed(&stationary, scan, 0.01, 0.01));
+        let fast = history(pose_at);
+        assert!(deskew_needed(&fast, scan, 0.01, 0.01));
+    }
+
+    #[test]
+    fn returns_of_one_beam_are_deskewed_at_their_own_times() {
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let raw = Vector3::new(5.0, 2.0, 1.0);
+        let point = |ms, return_number| Point3d {
+            return_number,
+            ..Point3d::new_with_timestamp(raw.x as f32, raw.y as f32, raw.z as f32, 1.0, at(ms))
+        };
+        // both returns stamped with the firing time, the scan ends at 100ms
+        let mut points = vec![point(50, 0), point(50, 1), point(100, 0)];
+        apply_return_time_offsets(&mut points, &[0.0, 0.02]);
+        assert_eq!(points[0].timestamp(), at(50));
+        assert_eq!(points[1].timestamp(), at(70));
+
+        let poses: Vec<_> = (0..=100)
+            .step_by(10)
+            .map(|ms| (at(ms), pose_at(ms as f64 / 1000.0)))
+            .collect();
+        deskew_scan(&mut points, &poses, 0.2, 0.05).unwrap();
+        let end_from_map = pose_at(0.1).inverse();
+        for (pt, seconds) in points.iter().zip([0.05, 0.07]) {
+            let expected = end_from_map * pose_at(seconds).transform_point(&raw.into());
+            assert!((pt.position() - expected.coords).norm() < 1e-4);
+        }
+
+        // invalid offsets leave the timestamps untouched
+        let mut points = vec![point(50, 0)];
+        apply_return_time_offsets(&mut points, &[f64::NAN]);
+        assert_eq!(points[0].timestamp(), at(50));
+    }
+
+    /// Points on the wall x = 5 of the world, each observed at its own time from
+    /// `pose(seconds)` over a 100ms sweep.
+    fn wall_sweep(t0: Instant, pose: impl Fn(f64) -> Isometry3<f64>) -> Vec<Point3d> {
+        (0..=20)
+            .map(|i| {
+                let ms = 5 * i;
+                let world = nalgebra::Point3::new(5.0, -2.0 + 0.2 * i as f64, 0.5);
+                let raw = pose(ms as f64 / 1000.0).inverse_transform_point(&world);
+                Point3d::new_with_timestamp(
+                    raw.x as f32,
+                    raw.y as f32,
+                    raw.z as f32,
+                    1.0,
+                    t0 + Duration::from_millis(ms),
+                )
+            })
+            .collect()
+    }
+
+    /// Largest distance of the deskewed points to the wall, seen from `scan_end`.
+    fn wall_error(points: &[Point3d], scan_end: &Isometry3<f64>) -> f64 {
+        points
+            .iter()
+            .map(|pt| (scan_end.transform_point(&pt.position().into()).x - 5.0).abs())
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn imu_deskew_straightens_a_rotating_sweep() {
+        let t0 = Instant::now();
+        let mut points = wall_sweep(t0, pose_at);
+        assert!(wall_error(&points, &pose_at(0.1)) > 0.1);
+
+        // pose_at as seen by a level IMU: constant yaw rate, no acceleration, the
+        // velocity only known from the start velocity
+        let imu: Vec<ImuSample> = (0..=12)
+            .map(|i| {
+                (
+                    t0 + Duration::from_millis(10 * i),
+                    Vector3::z(),
+                    Vector3::new(0.0, 0.0, GRAVITY),
+                )
+            })
+            .collect();
+        let result = deskew_scan_imu(&mut points, &pose_at(0.0), &Vector3::x(), &imu).unwrap();
+        assert_eq!(result.num_corrected, points.len());
+        assert!(wall_error(&points, &pose_at(0.1)) < 1e-4);
+
+        // without the start velocity only the rotation is undone
+        let mut points = wall_sweep(t0, pose_at);
+        deskew_scan_imu(&mut points, &pose_at(0.0), &Vector3::zeros(), &imu).unwrap();
+        assert!(wall_error(&points, &pose_at(0.1)) > 0.05);
+    }
+
+    #[test]
+    fn constant_velocity_fallback_straightens_a_sweep_after_a_discontinuity() {
+        // a constant body twist of 1 m/s forward and 1 rad/s yaw
+        let twist =
+            |seconds: f64| (nalgebra::Vector6::new(1.0, 0.0, 0.0, 0.0, 0.0, 1.0) * seconds).exp();
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        // a relocalization jump, then two poses ending before the scan starts
+        let poses = [
+            (at(0), Isometry3::translation(10.0, 0.0, 0.0)),
+            (at(40), twist(-0.06)),
+            (at(50), twist(-0.05)),
+        ];
+        // the scan starts at 100ms, the twist origin
+        let sweep = wall_sweep(at(100), twist);
+
+        let mut points = sweep.clone();
+        assert_eq!(
+            deskew_scan_with_mode(&mut points, &poses, 0.2, 0.05, DeskewMode::Interpolate),
+            Err(DeskewError::InvalidPoses)
+        );
+
+        let mut points = sweep;
+        assert!(wall_error(&points, &twist(0.1)) > 0.1);
+        let result = deskew_scan_with_mode(
+            &mut points,
+            &poses,
+            0.2,
+            0.05,
+            DeskewMode::ConstantVelocityFallback,
+        )
+        .unwrap();
+        assert_eq!(result.num_corrected, points.len());
+        assert!(wall_error(&points, &twist(0.1)) < 1e-4);
+    }
+
+    #[test]
+    fn unix_time_poses_deskew_like_instant_poses() {
+        let t0 = Instant::now();
+        let sweep = wall_sweep(t0, pose_at);
+        // the anchor is the middle of the scan, so points fall on both sides of it
+        let clock_anchor = (t0 + Duration::from_millis(50), 1_700_000_000.05);
+        let instant_poses: Vec<_> = (0..=10)
+            .map(|i| (t0 + Duration::from_millis(10 * i), pose_at(0.01 * i as f64)))
+            .collect();
+        let unix_poses: Vec<_> = (0..=10)
+            .map(|i| (1_700_000_000.0 + 0.01 * i as f64, pose_at(0.01 * i as f64)))
+            .collect();
+
+        let mut expected = sweep.clone();
+        deskew_scan(&mut expected, &instant_poses, 0.2, 0.05).unwrap();
+        let mut points = sweep;
+        let result = deskew_scan_unix(&mut points, &unix_poses, clock_anchor, 0.2, 0.05).unwrap();
+        assert_eq!(result.num_corrected, points.len());
+        for (pt, expected) in points.iter().zip(&expected) {
+            assert!((pt.position() - expected.position()).norm() < 1e-5);
+        }
+        assert!(wall_error(&points, &pose_at(0.1)) < 1e-4);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{error::Error, fmt, io};
+
+/// Crate-level error, every subsystem error converts into it so `?` works across modules.
+#[derive(Debug)]
+pub enum IcpError {
+    Config(ConfigError),
+    Deskew(DeskewError),
+    Io(io::Error),
+    Registration(RegistrationError),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// One message per offending field
+    Invalid(Vec<String>),
+    /// The config file could not be read
+    Read(String),
+    /// The config file is not valid TOML/YAML for a `Config`
+    Parse(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeskewError {
+    EmptyPoints,
+    EmptyPoses,
+    /// Poses are out of order, too far apart or not covering the scan
+    InvalidPoses,
+    EmptyImuSamples,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationError {
+    InsufficientCorrespondences {
+        found: usize,
+        required: usize,
+    },
+    /// The normal equations could not be solved
+    SingularSystem,
+}
+
+impl fmt::Display for IcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IcpError::Config(e) => write!(f, "config error: {e}"),
+            IcpError::Deskew(e) => write!(f, "deskew error: {e}"),
+            IcpError::Io(e) => write!(f, "io error: {e}"),
+            IcpError::Registration(e) => write!(f, "registration error: {e}"),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Invalid(fields) => write!(f, "invalid values: {}", fields.join(", ")),
+            ConfigError::Read(e) => write!(f, "could not read config: {e}"),
+            ConfigError::Parse(e) => write!(f, "could not parse config: {e}"),
+        }
+    }
+}
+
+impl fmt::Display for DeskewError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeskewError::EmptyPoints => write!(f, "no points to deskew"),
+            DeskewError::EmptyPoses => write!(f, "no poses to interpolate"),
+            DeskewError::InvalidPoses => write!(f, "poses are unordered or discontinuous"),
+            DeskewError::EmptyImuSamples => write!(f, "no IMU samples to integrate"),
+        }
+    }
+}
+
+impl fmt::Display for RegistrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrationError::InsufficientCorrespondences { found, required } => write!(
+                f,
+                "found {found} correspondences, at least {required} required"
+            ),
+            RegistrationError::SingularSystem => write!(f, "linear system is singular"),
+        }
+    }
+}
+
+impl Error for IcpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IcpError::Config(e) => Some(e),
+            IcpError::Deskew(e) => Some(e),
+            IcpError::Io(e) => Some(e),
+            IcpError::Registration(e) => Some(e),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+impl Error for DeskewError {}
+impl Error for RegistrationError {}
+
+impl From<ConfigError> for IcpError {
+    fn from(e: ConfigError) -> Self {
+        IcpError::Config(e)
+    }
+}
+
+impl From<DeskewError> for IcpError {
+    fn from(e: DeskewError) -> Self {
+        IcpError::Deskew(e)
+    }
+}
+
+impl From<io::Error> for IcpError {
+    fn from(e: io::Error) -> Self {
+        IcpError::Io(e)
+    }
+}
+
+impl From<RegistrationError> for IcpError {
+    fn from(e: RegistrationError) -> Self {
+        IcpError::Registration(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sub_errors_convert_into_icp_error() {
+        let errors: [(IcpError, &str); 4] = [
+            (
+                ConfigError::Invalid(vec!["voxel_size must be > 0, got 0".to_string()]).into(),
+                "config error: invalid values: voxel_size must be > 0, got 0",
+            ),
+            (
+                DeskewError::EmptyPoses.into(),
+                "deskew error: no poses to interpolate",
+            ),
+            (
+                io::Error::new(io::ErrorKind::NotFound, "map.bin").into(),
+                "io error: map.bin",
+            ),
+            (
+                RegistrationError::InsufficientCorrespondences {
+                    found: 3,
+                    required: 10,
+                }
+                .into(),
+                "registration error: found 3 correspondences, at least 10 required",
+            ),
+        ];
+        for (error, message) in errors {
+            assert_eq!(error.to_string(), message);
+            assert!(error.source().is_some());
+        }
+    }
+}
@@ -1,60 +1,275 @@
-use std::collections::HashMap;
-
 use crate::{
     adaptive_threshold::AdaptiveThreshold,
-    config,
+    config::{self, TimestampOverlapPolicy},
+    deskew::DeskewResult,
+    error::{ConfigError, DeskewError, RegistrationError},
     lie_group::{Exp, Hat},
-    point3d, voxel_hash_map, voxel_util,
+    point3d::{self, PointLike},
+    range_image::RangeImageMatcher,
+    voxel_hash_map, voxel_util,
 };
 use nalgebra::{self as na, Vector3};
+use std::time::Instant;
+
+/// Outcome of aligning a scan to the map.
+#[derive(Debug, Clone)]
+pub struct IcpResult {
+    pub pose: na::Isometry3<f64>,
+    pub num_iterations: u16,
+    /// Correspondences found in the last iteration
+    pub num_correspondences: usize,
+    /// Fraction of source points with a correspondence in the last iteration
+    pub inlier_ratio: f64,
+    pub converged: bool,
+    /// Gauss-Newton covariance of the pose from the last iteration, ordered
+    /// [translation x y z, rotation x y z] for a perturbation applied on the left,
+    /// in the map frame: `pose = exp(delta) * estimate`. None when underdetermined.
+    pub covariance: Option<na::Matrix6<f64>>,
+    /// Set when the scan was not registered nor inserted into the map
+    pub rejection: Option<ScanRejection>,
+    /// Fit quality at the final pose, None when no registration ran
+    pub stats: Option<RegistrationStats>,
+    /// Latest point timestamp of the scan, the time of `pose` (set by the pipeline)
+    pub scan_end: Option<Instant>,
+    /// Outcome of deskewing the scan, None when it was not attempted: deskew disabled
+    /// or negligible motion (set by the pipeline). The scan is used as is on error
+    pub deskew: Option<Result<DeskewResult, DeskewError>>,
+}
+
+/// Correspondences of the scan at its registered pose, gated by the adaptive
+/// threshold, to judge whether to trust a registration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegistrationStats {
+    pub rmse: f64,
+    pub mean_distance: f64,
+    pub median_distance: f64,
+    /// Fraction of source points with a correspondence
+    pub inlier_ratio: f64,
+    pub num_correspondences: usize,
+}
+
+impl RegistrationStats {
+    /// Stats of the correspondence `distances` of `num_points` source points.
+    pub(crate) fn from_distances(distances: &mut [f64], num_points: usize) -> Self {
+        let n = distances.len();
+        let (rmse, mean_distance, median_distance) = if n == 0 {
+            (0.0, 0.0, 0.0)
+        } else {
+            let rmse = (distances.iter().map(|d| d * d).sum::<f64>() / n as f64).sqrt();
+            let mean = distances.iter().sum::<f64>() / n as f64;
+            let (_, median, _) = distances.select_nth_unstable_by(n / 2, f64::total_cmp);
+            (rmse, mean, *median)
+        };
+        RegistrationStats {
+            rmse,
+            mean_distance,
+            median_distance,
+            inlier_ratio: if num_points == 0 {
+                0.0
+            } else {
+                n as f64 / num_points as f64
+            },
+            num_correspondences: n,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanRejection {
+    /// Valid point count deviates from the running average by more than the configured factor
+    PointCountJump { count: usize, average: f64 },
+    /// Registration was aborted, the pose is the initial guess
+    Registration(RegistrationError),
+}
+
+/// Picks the result with the highest inlier ratio, e.g. among multi-start registrations.
+/// A NaN ratio ranks lowest.
+pub fn select_best(results: &[IcpResult]) -> Option<&IcpResult> {
+    let ratio = |result: &IcpResult| {
+        if result.inlier_ratio.is_nan() {
+            f64::NEG_INFINITY
+        } else {
+            result.inlier_ratio
+        }
+    };
+    results.iter().max_by(|a, b| ratio(a).total_cmp(&ratio(b)))
+}
 
-pub struct IcpPipeline {
+/// Buffers of the registration loop, kept between iterations and scans so a
+/// real-time loop doesn't allocate once they have grown to the scan size.
+///
+/// Create one per registration thread and pass it to every
+/// [`register_scan_with_workspace`] call; its content between calls is meaningless.
+pub struct IcpWorkspace<P = point3d::Point3d> {
+    source: Vec<P>,
+    correspondences: Vec<(P, P)>,
+    normals: Vec<Option<na::Vector3<f64>>>,
+    color_gradients: Vec<Option<na::Vector3<f64>>>,
+    distances: Vec<f64>,
+    association: AssociationPool,
+}
+
+impl<P> IcpWorkspace<P> {
+    pub fn new() -> Self {
+        IcpWorkspace {
+            source: Vec::new(),
+            correspondences: Vec::new(),
+            normals: Vec::new(),
+            color_gradients: Vec::new(),
+            distances: Vec::new(),
+            association: AssociationPool::default(),
+        }
+    }
+}
+
+impl<P> Default for IcpWorkspace<P> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Registers a scan (in sensor frame) against `voxel_map` starting from `initial_guess`,
+/// with correspondence gating derived from `config.initial_threshold`.
+pub fn register_scan<P: PointLike>(
+    scan: &[P],
+    voxel_map: &voxel_hash_map::VoxelHashMap<P>,
+    config: &config::Config,
+    initial_guess: &na::Isometry3<f64>,
+) -> IcpResult {
+    register_scan_with_workspace(
+        scan,
+        voxel_map,
+        config,
+        initial_guess,
+        &mut IcpWorkspace::new(),
+    )
+}
+
+/// [`register_scan`] reusing the buffers of `workspace`.
+pub fn register_scan_with_workspace<P: PointLike>(
+    scan: &[P],
+    voxel_map: &voxel_hash_map::VoxelHashMap<P>,
+    config: &config::Config,
+    initial_guess: &na::Isometry3<f64>,
+    workspace: &mut IcpWorkspace<P>,
+) -> IcpResult {
+    register_scan_with_callback(scan, voxel_map, config, initial_guess, workspace, |_| {})
+}
+
+/// Progress of one ICP iteration, for convergence debugging.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IterationInfo {
+    pub iteration: u16,
+    /// Norms of the translation (meters) and rotation (radians) of the applied update
+    pub translation_delta: f64,
+    pub rotation_delta: f64,
+    /// Root mean square correspondence distance before the update
+    pub rmse: f64,
+    pub num_correspondences: usize,
+}
+
+/// [`register_scan_with_workspace`] calling `on_iter` after every iteration.
+pub fn register_scan_with_callback<P: PointLike>(
+    scan: &[P],
+    voxel_map: &voxel_hash_map::VoxelHashMap<P>,
+    config: &config::Config,
+    initial_guess: &na::Isometry3<f64>,
+    workspace: &mut IcpWorkspace<P>,
+    on_iter: impl FnMut(IterationInfo),
+) -> IcpResult {
+    let sigma = config.initial_threshold;
+    align_points_to_map(
+        scan,
+        voxel_map,
+        initial_guess,
+        sigma,
+        config,
+        workspace,
+        on_iter,
+    )
+}
+
+/// Registers from each guess in turn until one reaches `config.min_inlier_ratio`,
+/// returning the best result by inlier ratio. Returns `None` without guesses.
+pub fn register_scan_with_retry<P: PointLike>(
+    scan: &[P],
+    voxel_map: &voxel_hash_map::VoxelHashMap<P>,
+    config: &config::Config,
+    guesses: &[na::Isometry3<f64>],
+) -> Option<IcpResult> {
+    let mut results = Vec::with_capacity(guesses.len());
+    let mut workspace = IcpWorkspace::new();
+    for guess in guesses {
+        let result = register_scan_with_workspace(scan, voxel_map, config, guess, &mut workspace);
+        let good_enough = result.inlier_ratio >= config.min_inlier_ratio;
+        results.push(result);
+        if good_enough {
+            break;
+        }
+    }
+    select_best(&results).cloned()
+}
+
+pub struct IcpPipeline<P = point3d::Point3d> {
     config: config::Config,
     pub t_origin_current: na::Isometry3<f64>,
     t_prev_current: na::Isometry3<f64>,
-    voxel_map: voxel_hash_map::VoxelHashMap,
+    voxel_map: voxel_hash_map::VoxelHashMap<P>,
     adaptive_threshold: AdaptiveThreshold,
+    average_point_count: Option<f64>,
+    num_point_count_rejections: usize,
+    last_scan_end: Option<Instant>,
+    workspace: IcpWorkspace<P>,
 }
 
-impl IcpPipeline {
+impl<P: PointLike> IcpPipeline<P> {
+    /// Pipeline for a validated `config`, see [`config::Config::validate`].
+    pub fn try_new_with_config(config: config::Config) -> Result<Self, ConfigError> {
+        config.validate()?;
+        Ok(Self::new_with_config(config))
+    }
+    /// Pipeline for `config` as is, prefer [`IcpPipeline::try_new_with_config`] for
+    /// configs that were not checked.
     pub fn new_with_config(config: config::Config) -> Self {
         IcpPipeline {
             config: config.clone(),
             t_origin_current: na::Isometry::identity(),
             t_prev_current: na::Isometry::identity(),
             voxel_map: voxel_hash_map::VoxelHashMap {
-                voxel_size: config.voxel_size,
+                voxel_size: na::Vector3::repeat(config.voxel_size),
                 max_distance: config.max_range as f64,
                 max_points_per_voxel: config.max_points_per_voxel as usize,
-                map: HashMap::new(),
-                last_batch_points: Vec::new(),
+                max_adjacent_voxels: config.max_adjacent_voxels as i32,
                 max_point_age_seconds: config.max_point_age_seconds,
+                age_from_insert: config.age_from_insert,
+                intensity_blend: config.intensity_blend,
+                ..voxel_hash_map::VoxelHashMap::default_values()
             },
             adaptive_threshold: AdaptiveThreshold::new(
                 config.initial_threshold,
                 config.min_motion_th,
                 config.max_range as f64,
             ),
+            average_point_count: None,
+            num_point_count_rejections: 0,
+            last_scan_end: None,
+            workspace: IcpWorkspace::new(),
         }
     }
     pub fn get_global_map(&self) -> Vec<Vector3<f64>> {
-        return self.voxel_map.get_na_points();
+        let origin = self.voxel_map.origin;
+        let mut points = self.voxel_map.get_na_points();
+        points.iter_mut().for_each(|p| *p += origin);
+        points
     }
-    pub fn default_values() -> IcpPipeline {
-        let config = config::Config::default_values();
-        IcpPipeline {
-            config: config.clone(),
-            t_origin_current: na::Isometry::identity(),
-            t_prev_current: na::Isometry::identity(),
-            voxel_map: voxel_hash_map::VoxelHashMap::default_values(),
-            adaptive_threshold: AdaptiveThreshold::new(
-                config.initial_threshold,
-                config.min_motion_th,
-                config.max_range as f64,
-            ),
-        }
+    /// Pose in the global frame, `t_origin_current` being relative to the map origin.
+    pub fn global_pose(&self) -> na::Isometry3<f64> {
+        na::Translation3::from(self.voxel_map.origin) * self.t_origin_current
+    }
+    pub fn default_values() -> Self {
+        Self::new_with_config(config::Config::default_values())
     }
-    pub fn get_last_batch_points(&self) -> &Vec<point3d::Point3d> {
+    pub fn get_last_batch_points(&self) -> &Vec<P> {
         &self.voxel_map.last_batch_points
     }
 
@@ -73,47 +288,106 @@ impl IcpPipeline {
     // point cloud is mutable for deskewing in place
     pub fn process_frame(
         &mut self,
-        point_cloud: &mut [point3d::Point3d],
+        point_cloud: &mut [P],
         min_intensity: f32,
-        poses: &[(std::time::Instant, na::Isometry3<f64>)],
-    ) {
-        // deskew
-        if self.config.deskew {
-            crate::deskew::deskew_scan(
+        poses: &[(Instant, na::Isometry3<f64>)],
+    ) -> IcpResult {
+        // constant velocity prediction
+        let motion_guess = self.t_prev_current;
+        self.process_frame_with_motion_guess(point_cloud, min_intensity, poses, &motion_guess)
+    }
+
+    /// [`IcpPipeline::process_frame`] starting ICP from `motion_guess`, the motion
+    /// since the previous scan in its body frame (e.g. a wheel odometry increment),
+    /// instead of the constant velocity prediction. The guess replaces the prediction
+    /// for this scan only and the adaptive threshold measures its error, while the
+    /// velocity used by later scans is still the registered motion.
+    pub fn process_frame_with_motion_guess(
+        &mut self,
+        point_cloud: &mut [P],
+        min_intensity: f32,
+        poses: &[(Instant, na::Isometry3<f64>)],
+        motion_guess: &na::Isometry3<f64>,
+    ) -> IcpResult {
+        if self.config.deskew && !self.config.return_time_offsets.is_empty() {
+            crate::deskew::apply_return_time_offsets(point_cloud, &self.config.return_time_offsets);
+        }
+        // deskew, unless the motion during the scan is negligible
+        let deskew = (self.config.deskew
+            && self.scan_motion_exceeds_thresholds(point_cloud, poses))
+        .then(|| {
+            crate::deskew::deskew_scan_with_mode(
                 point_cloud,
                 poses,
                 self.config.max_angle_between_poses,
                 self.config.max_distance_between_poses,
-            );
-        }
+                self.config.deskew_mode,
+            )
+        });
         // clip distance
-        let cropped_frame = point3d::clip_point_cloud_by_distance_and_intensity(
+        let mut cropped_frame = point3d::clip_point_cloud_by_distance_and_intensity(
             point_cloud,
             self.config.min_range,
             self.config.max_range,
             min_intensity,
         );
+        let scan_end = point_cloud.iter().map(|p| p.timestamp()).max();
+        if self.config.drop_origin_points {
+            cropped_frame = point3d::remove_origin_points(&cropped_frame);
+        }
+        if self.config.timestamp_overlap == TimestampOverlapPolicy::Drop {
+            cropped_frame.retain(|p| !self.overlaps_previous_scan(p));
+        }
+
+        // reject scans whose point count jumps away from the running average
+        if let Some(rejection) = self.check_point_count(cropped_frame.len()) {
+            return IcpResult {
+                pose: self.t_origin_current,
+                num_iterations: 0,
+                num_correspondences: 0,
+                inlier_ratio: 0.0,
+                converged: false,
+                covariance: None,
+                rejection: Some(rejection),
+                stats: None,
+                scan_end,
+                deskew,
+            };
+        }
 
         // voxelize
-        let (source, frame_downsample) = voxelize(&cropped_frame, self.config.voxel_size);
+        let (source, mut frame_downsample) = voxelize(&cropped_frame, self.config.voxel_size);
+        if self.config.timestamp_overlap == TimestampOverlapPolicy::RegisterOnly {
+            frame_downsample.retain(|p| !self.overlaps_previous_scan(p));
+        }
 
         // get adaptive threshold
         let sigma = self.adaptive_threshold.compute_threshold();
         // let sigma = 2.0;
 
         // initial guess
-        let t_origin_next_init = self.t_origin_current * self.t_prev_current;
+        let t_origin_next_init = self.t_origin_current * motion_guess;
 
         // Run ICP
-        let t_origin_next = align_points_to_map(
+        let result = align_points_to_map(
             &source,
             &self.voxel_map,
             &t_origin_next_init,
-            3.0 * sigma,
-            sigma / 3.0,
-            self.config.max_num_iterations,
-            self.config.convergence_criterion,
+            sigma,
+            &self.config,
+            &mut self.workspace,
+            |_| {},
         );
+        // hold the last pose, the scan is neither registered nor inserted
+        if result.rejection.is_some() {
+            return IcpResult {
+                pose: self.t_origin_current,
+                scan_end,
+                deskew,
+                ..result
+            };
+        }
+        let t_origin_next = result.pose;
 
         // Compute the difference between the prediction and the actual estimate
         let model_deviation = t_origin_next_init.inverse() * t_origin_next;
@@ -121,9 +395,23 @@ impl IcpPipeline {
         // Update step: threshold, local map, delta, and the last pose
         self.adaptive_threshold
             .update_model_deviation(&model_deviation);
-        self.voxel_map
-            .update_with_pose(&frame_downsample, &t_origin_next);
+        // only well registered scans get merged, the others just track the pose
+        if self.voxel_map.is_empty()
+            || self.config.min_overlap_to_insert <= 0.0
+            || (result.inlier_ratio >= self.config.min_overlap_to_insert
+                && self
+                    .voxel_map
+                    .estimate_overlap(&source, &t_origin_next, 3.0 * sigma)
+                    >= self.config.min_overlap_to_insert)
+        {
+            self.voxel_map.update_with_pose(
+                &frame_downsample,
+                &t_origin_next,
+                &na::Isometry3::identity(),
+            );
+        }
         self.t_prev_current = self.t_origin_current.inverse() * t_origin_next;
+        self.last_scan_end = self.last_scan_end.max(scan_end);
 
         // bug in nalgebra
         self.t_origin_current = na::Isometry3::from_parts(
@@ -131,55 +419,305 @@ impl IcpPipeline {
             na::UnitQuaternion::from_quaternion(t_origin_next.rotation.normalize()),
         );
 
-        // Return the (deskew) input raw scan (frame) and the points used for registration (source)
-        // (point_cloud, source)
+        if let Some(recenter_distance) = self.config.recenter_distance {
+            let translation = &mut self.t_origin_current.translation.vector;
+            if translation.norm() > recenter_distance {
+                *translation -= self.voxel_map.recenter(translation);
+            }
+        }
+
+        IcpResult {
+            pose: self.t_origin_current,
+            scan_end,
+            deskew,
+            ..result
+        }
+    }
+
+    fn overlaps_previous_scan(&self, point: &P) -> bool {
+        self.last_scan_end
+            .is_some_and(|last_end| point.timestamp() <= last_end)
+    }
+
+    fn scan_motion_exceeds_thresholds(
+        &self,
+        point_cloud: &[P],
+        poses: &[(Instant, na::Isometry3<f64>)],
+    ) -> bool {
+        let start = point_cloud.iter().map(|p| p.timestamp()).min();
+        let end = point_cloud.iter().map(|p| p.timestamp()).max();
+        let (Some(start), Some(end)) = (start, end) else {
+            return false;
+        };
+        crate::deskew::deskew_needed(
+            poses,
+            end.duration_since(start),
+            self.config.deskew_min_translation,
+            self.config.deskew_min_rotation,
+        )
+    }
+
+    fn check_point_count(&mut self, count: usize) -> Option<ScanRejection> {
+        let max_ratio = self.config.max_point_count_ratio?;
+        let count_f = count as f64;
+        let Some(average) = self.average_point_count else {
+            self.average_point_count = Some(count_f);
+            return None;
+        };
+        let jump = count_f * max_ratio < average || count_f > average * max_ratio;
+        if jump && self.num_point_count_rejections < POINT_COUNT_MAX_REJECTIONS {
+            self.num_point_count_rejections += 1;
+            return Some(ScanRejection::PointCountJump { count, average });
+        }
+        // a jump outlasting the rejections is a lasting change, e.g. open terrain
+        self.average_point_count = Some(if jump {
+            count_f
+        } else {
+            average + POINT_COUNT_AVERAGE_ALPHA * (count_f - average)
+        });
+        self.num_point_count_rejections = 0;
+        None
     }
 }
 
-fn voxelize(
-    point_cloud: &[point3d::Point3d],
-    voxel_size: f32,
-) -> (Vec<point3d::Point3d>, Vec<point3d::Point3d>) {
-    let frame_downsample = voxel_util::voxel_downsample(point_cloud, voxel_size * 0.5);
-    let source = voxel_util::voxel_downsample(&point_cloud, voxel_size * 1.5);
-    (source, frame_downsample)
+// smoothing factor of the running average of valid points per scan
+const POINT_COUNT_AVERAGE_ALPHA: f64 = 0.1;
+// consecutive point count rejections before the average restarts from the new count
+const POINT_COUNT_MAX_REJECTIONS: usize = 3;
+
+fn voxelize<P: PointLike>(point_cloud: &[P], voxel_size: f32) -> (Vec<P>, Vec<P>) {
+    voxel_util::voxel_downsample_double(point_cloud, voxel_size * 1.5, voxel_size * 0.5)
 }
 
-fn transform_points(transform: &na::Isometry3<f64>, point_cloud: &mut [point3d::Point3d]) {
+pub(crate) fn transform_points<P: PointLike>(
+    transform: &na::Isometry3<f64>,
+    point_cloud: &mut [P],
+) {
     point_cloud.iter_mut().for_each(|pt| {
-        let transformed_pt =
-            transform.transform_point(&na::Point3::new(pt.x as f64, pt.y as f64, pt.z as f64));
-        pt.x = transformed_pt.x as f32;
-        pt.y = transformed_pt.y as f32;
-        pt.z = transformed_pt.z as f32;
+        let transformed_pt = transform.transform_point(&pt.position().into());
+        *pt = pt.with_position(&transformed_pt.coords);
     });
 }
 
-fn point_association(
-    points: &[point3d::Point3d],
-    voxel_map: &voxel_hash_map::VoxelHashMap,
+/// Streams the correspondences of `source` (already in map frame) with their
+/// distance to `f`, without collecting them.
+pub fn for_each_correspondence<P: PointLike>(
+    source: &[P],
+    voxel_map: &voxel_hash_map::VoxelHashMap<P>,
     max_correspondance_distance: f64,
-) -> Vec<(point3d::Point3d, point3d::Point3d)> {
-    points
-        .iter()
-        .filter_map(|pt| {
-            if let Some((closest_neighbor, distance)) = voxel_map.get_closest_neighbor(pt) {
-                if distance < max_correspondance_distance {
-                    Some((pt.to_owned(), closest_neighbor))
-                } else {
-                    None
-                }
-            } else {
-                None
+    f: impl FnMut(&P, &P, f64),
+) {
+    for_each_association(
+        source,
+        |pt| voxel_map.get_closest_neighbor_within(pt, max_correspondance_distance),
+        max_correspondance_distance,
+        None,
+        f,
+    );
+}
+
+fn for_each_association<P: PointLike>(
+    points: &[P],
+    find_neighbor: impl Fn(&P) -> Option<(P, f64)>,
+    max_correspondance_distance: f64,
+    max_intensity_diff: Option<f32>,
+    mut f: impl FnMut(&P, &P, f64),
+) {
+    for pt in points {
+        if let Some((closest_neighbor, distance)) = associate(
+            pt,
+            &find_neighbor,
+            max_correspondance_distance,
+            max_intensity_diff,
+        ) {
+            f(pt, &closest_neighbor, distance);
+        }
+    }
+}
+
+/// Closest neighbor of `pt` with its distance, if it passes the distance and
+/// intensity gates.
+#[inline]
+fn associate<P: PointLike>(
+    pt: &P,
+    find_neighbor: &impl Fn(&P) -> Option<(P, f64)>,
+    max_correspondance_distance: f64,
+    max_intensity_diff: Option<f32>,
+) -> Option<(P, f64)> {
+    let (closest_neighbor, distance) = find_neighbor(pt)?;
+    let intensity_matches = max_intensity_diff
+        .is_none_or(|max_diff| (pt.intensity() - closest_neighbor.intensity()).abs() <= max_diff);
+    (distance < max_correspondance_distance && intensity_matches)
+        .then_some((closest_neighbor, distance))
+}
+
+/// Runs the correspondence search, on a thread pool sized by
+/// `Config::max_num_threads` with the `rayon` feature.
+#[derive(Default)]
+struct AssociationPool {
+    #[cfg(feature = "rayon")]
+    pool: Option<rayon::ThreadPool>,
+}
+
+impl AssociationPool {
+    #[cfg(not(feature = "rayon"))]
+    fn point_association<P: PointLike>(
+        &mut self,
+        _num_threads: u8,
+        points: &[P],
+        find_neighbor: &(impl Fn(&P) -> Option<(P, f64)> + Sync),
+        max_correspondance_distance: f64,
+        max_intensity_diff: Option<f32>,
+        correspondences: &mut Vec<(P, P)>,
+    ) {
+        correspondences.clear();
+        for_each_association(
+            points,
+            find_neighbor,
+            max_correspondance_distance,
+            max_intensity_diff,
+            |source, target, _| correspondences.push((*source, *target)),
+        );
+    }
+
+    /// The map is only read during the search, so points are looked up in parallel.
+    /// Correspondences keep the order of `points`.
+    #[cfg(feature = "rayon")]
+    fn point_association<P: PointLike>(
+        &mut self,
+        num_threads: u8,
+        points: &[P],
+        find_neighbor: &(impl Fn(&P) -> Option<(P, f64)> + Sync),
+        max_correspondance_distance: f64,
+        max_intensity_diff: Option<f32>,
+        correspondences: &mut Vec<(P, P)>,
+    ) {
+        use rayon::prelude::*;
+
+        // 0 uses the global pool, spanning all cores
+        let num_threads = num_threads as usize;
+        if num_threads == 0 {
+            self.pool = None;
+        } else if self
+            .pool
+            .as_ref()
+            .is_none_or(|pool| pool.current_num_threads() != num_threads)
+        {
+            self.pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(num_threads)
+                .build()
+                .ok();
+        }
+        correspondences.clear();
+        let mut search = || {
+            correspondences.par_extend(points.par_iter().filter_map(|pt| {
+                associate(
+                    pt,
+                    find_neighbor,
+                    max_correspondance_distance,
+                    max_intensity_diff,
+                )
+                .map(|(target, _)| (*pt, target))
+            }))
+        };
+        match &self.pool {
+            Some(pool) => pool.install(search),
+            None => search(),
+        }
+    }
+}
+
+/// Normal of each correspondence for the point-to-plane residual, in map frame.
+fn correspondence_normals<P: PointLike>(
+    correspondences: &[(P, P)],
+    source: &[P],
+    voxel_map: &voxel_hash_map::VoxelHashMap<P>,
+    config: &config::Config,
+    normals: &mut Vec<Option<na::Vector3<f64>>>,
+) {
+    // the source cloud is sparser (see voxelize) so its normals need a wider support
+    let source_radius = 3.0 * config.voxel_size as f64;
+    let source_grid = match config.normal_source {
+        config::NormalSource::Target => None,
+        config::NormalSource::Source | config::NormalSource::Both => {
+            let mut grid = voxel_hash_map::VoxelHashMap::<P> {
+                voxel_size: na::Vector3::repeat(source_radius as f32),
+                ..voxel_hash_map::VoxelHashMap::default_values()
+            };
+            source
+                .iter()
+                .for_each(|pt| grid.map.entry(grid.voxel_of(pt)).or_default().push(*pt));
+            Some(grid)
+        }
+    };
+    let target_normal = |pt: &P| voxel_map.estimate_normal(pt, None);
+    let source_normal = |pt: &P| {
+        let grid = source_grid.as_ref()?;
+        voxel_util::estimate_normal(&grid.neighbor_positions(&pt.position(), source_radius))
+    };
+    normals.clear();
+    normals.extend(correspondences.iter().map(|(source, target)| {
+        match config.normal_source {
+            config::NormalSource::Target => target_normal(target),
+            config::NormalSource::Source => source_normal(source),
+            config::NormalSource::Both => {
+                let (n_s, n_t) = (source_normal(source)?, target_normal(target)?);
+                // normals have no consistent orientation, align them before summing
+                let n_s = if n_s.dot(&n_t) < 0.0 { -n_s } else { n_s };
+                (n_s + n_t).try_normalize(f64::EPSILON)
             }
-        })
-        .collect()
+        }
+    }));
+}
+
+fn luminance(rgb: [f32; 3]) -> f64 {
+    (rgb[0] + rgb[1] + rgb[2]) as f64 / 3.0
+}
+
+/// Luminance gradient on the tangent plane of each matched map point (colored ICP),
+/// fitted on its colored neighbors. `None` where the target has no usable color.
+fn correspondence_color_gradients<P: PointLike>(
+    correspondences: &[(P, P)],
+    voxel_map: &voxel_hash_map::VoxelHashMap<P>,
+    radius: f64,
+    gradients: &mut Vec<Option<na::Vector3<f64>>>,
+) {
+    gradients.clear();
+    gradients.extend(correspondences.iter().map(|(_, target)| {
+        let q = target.position();
+        let c_q = luminance(target.color()?);
+        let neighbors = voxel_map.neighbors(&q, radius);
+        let positions: Vec<_> = neighbors.iter().map(|pt| pt.position()).collect();
+        let normal = voxel_util::estimate_normal(&positions)?;
+        // least squares on C(p) - C(q) = d . (p' - q), p' being p projected on the
+        // tangent plane, with d . n = 0 as an extra equation
+        let mut ata = normal * normal.transpose() * neighbors.len() as f64;
+        let mut atb = na::Vector3::zeros();
+        let mut num_colored = 0;
+        for pt in &neighbors {
+            let Some(color) = pt.color() else {
+                continue;
+            };
+            let offset = pt.position() - q;
+            let a = offset - normal * normal.dot(&offset);
+            ata += a * a.transpose();
+            atb += a * (luminance(color) - c_q);
+            num_colored += 1;
+        }
+        if num_colored < 3 {
+            return None;
+        }
+        ata.cholesky().map(|chol| chol.solve(&atb))
+    }));
 }
 
-fn build_linear_system(
-    correspondences: &[(point3d::Point3d, point3d::Point3d)],
+fn build_linear_system<P: PointLike>(
+    correspondences: &[(P, P)],
+    normals: Option<&[Option<na::Vector3<f64>>]>,
+    color_gradients: Option<&[Option<na::Vector3<f64>>]>,
     kernel_scale: f64,
-) -> (na::Matrix6<f64>, na::Vector6<f64>) {
+    config: &config::Config,
+) -> LinearSystem {
     let compute_jacobian_and_residual =
         |(source, target): &(na::Vector3<f64>, na::Vector3<f64>)| {
             let redisual = source - target;
@@ -193,60 +731,337 @@ fn build_linear_system(
 
     correspondences
         .iter()
-        .map(|corr_p3d| {
+        .enumerate()
+        .map(|(i, corr_p3d)| {
             let square = |x| x * x;
-            let weight = |residual2| square(kernel_scale) / square(kernel_scale + residual2);
-            let corr = (corr_p3d.0.to_na_vec_f64(), corr_p3d.1.to_na_vec_f64());
-            let (j_r, residual) = compute_jacobian_and_residual(&corr);
-            let w = weight(residual.norm_squared());
+            let weight =
+                |residual2: f64| config.robust_kernel.weight(residual2.sqrt(), kernel_scale);
+            let corr = (corr_p3d.0.position(), corr_p3d.1.position());
+            let (mut j_r, mut residual) = compute_jacobian_and_residual(&corr);
+            let plane_normal = normals.and_then(|normals| normals[i]);
+            let is_point_to_plane = plane_normal.is_some();
+            if let Some(normal) = plane_normal {
+                // point-to-plane, keep only the component along the normal
+                let projection = normal * normal.transpose();
+                j_r = projection * j_r;
+                residual = projection * residual;
+            }
+            // photometric part of the residual, only affects the robust weight
+            let intensity_residual =
+                config.intensity_weight * (corr_p3d.0.intensity() - corr_p3d.1.intensity()) as f64;
+            // floor the weight so far outliers keep a tiny influence and JtJ stays full rank
+            let w =
+                weight(residual.norm_squared() + square(intensity_residual)).max(config.min_weight);
 
             let j_t = j_r.transpose();
             let j_tw = j_t * w;
-            let j_tj = j_tw * j_r;
-            let j_tr = j_tw * residual;
-            (j_tj, j_tr)
+            let mut j_tj = j_tw * j_r;
+            let mut j_tr = j_tw * residual;
+            let mut chi2 = w * residual.norm_squared();
+            // a point-to-plane correspondence only constrains the normal direction
+            let mut num_residuals = if is_point_to_plane { 1 } else { 3 };
+
+            let color_term = color_gradients
+                .and_then(|gradients| gradients[i])
+                .zip(corr_p3d.0.color().zip(corr_p3d.1.color()));
+            if let Some((gradient, (source_color, target_color))) = color_term {
+                // C(q) + d . (s - q) - C(s), d lies on the tangent plane of q
+                let color_residual = luminance(target_color) + gradient.dot(&(corr.0 - corr.1))
+                    - luminance(source_color);
+                // the point jacobian before any projection
+                let (j_point, _) = compute_jacobian_and_residual(&corr);
+                let j_c = gradient.transpose() * j_point;
+                let w_c = w * config.color_weight;
+                j_tj += j_c.transpose() * j_c * w_c;
+                j_tr += j_c.transpose() * (color_residual * w_c);
+                chi2 += w_c * square(color_residual);
+                num_residuals += 1;
+            }
+            LinearSystem {
+                jtj: j_tj,
+                jtr: j_tr,
+                chi2,
+                num_residuals,
+            }
+        })
+        .fold(LinearSystem::default(), |a, b| LinearSystem {
+            jtj: a.jtj + b.jtj,
+            jtr: a.jtr + b.jtr,
+            chi2: a.chi2 + b.chi2,
+            num_residuals: a.num_residuals + b.num_residuals,
         })
-        .fold(
-            (na::Matrix6::<f64>::zeros(), na::Vector6::<f64>::zeros()),
-            |(j_tj_a, j_tr_a), (j_tj_b, j_tr_b)| (j_tj_a + j_tj_b, j_tr_a + j_tr_b),
-        )
 }
 
-fn align_points_to_map(
-    point_cloud: &[point3d::Point3d],
-    voxel_map: &voxel_hash_map::VoxelHashMap,
-    initial_guess: &na::Isometry3<f64>,
-    max_distance: f64,
+/// Normal equations of an ICP iteration with the weighted squared residual sum.
+#[derive(Default)]
+struct LinearSystem {
+    jtj: na::Matrix6<f64>,
+    jtr: na::Vector6<f64>,
+    chi2: f64,
+    num_residuals: usize,
+}
+
+impl LinearSystem {
+    /// Gauss-Newton covariance `(J^T W J)^-1 * chi2 / (m - 6)`.
+    fn covariance(&self) -> Option<na::Matrix6<f64>> {
+        if self.num_residuals <= 6 {
+            return None;
+        }
+        let variance = self.chi2 / (self.num_residuals - 6) as f64;
+        self.jtj
+            .try_inverse()
+            .map(|information| information * variance)
+    }
+}
+
+/// Rejects an iteration with fewer correspondences than `config.min_correspondences`.
+pub(crate) fn check_correspondence_count(
+    num_correspondences: usize,
+    config: &config::Config,
+) -> Result<(), RegistrationError> {
+    match config.min_correspondences {
+        Some(required) if num_correspondences < required => {
+            Err(RegistrationError::InsufficientCorrespondences {
+                found: num_correspondences,
+                required,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Gauss-Newton step of one iteration, clamped to `config.max_step`, with the
+/// covariance of the system (see [`IcpResult::covariance`]). Shared by every
+/// registration so the same guards apply.
+pub(crate) fn solve_step<P: PointLike>(
+    correspondences: &[(P, P)],
+    normals: Option<&[Option<na::Vector3<f64>>]>,
+    color_gradients: Option<&[Option<na::Vector3<f64>>]>,
     kernel_scale: f64,
-    max_num_iterations: u16,
-    convergence_criterion: f64,
+    config: &config::Config,
+) -> Result<(na::Vector6<f64>, Option<na::Matrix6<f64>>), RegistrationError> {
+    check_correspondence_count(correspondences.len(), config)?;
+    let system = build_linear_system(
+        correspondences,
+        normals,
+        color_gradients,
+        kernel_scale,
+        config,
+    );
+    let dx = system
+        .jtj
+        .qr()
+        .solve(&(-system.jtr))
+        .ok_or(RegistrationError::SingularSystem)?;
+    let dx = match config.max_step {
+        Some(max_step) => clamp_step(dx, &max_step),
+        None => dx,
+    };
+    Ok((dx, system.covariance()))
+}
+
+/// Scales the translation and rotation parts of an se3 step down to at most
+/// `max_step.x` meters and `max_step.y` radians.
+fn clamp_step(mut dx: na::Vector6<f64>, max_step: &na::Vector2<f64>) -> na::Vector6<f64> {
+    for (start, max) in [(0, max_step.x), (3, max_step.y)] {
+        let mut part = dx.fixed_rows_mut::<3>(start);
+        let norm = part.norm();
+        if norm > max {
+            part *= max / norm;
+        }
+    }
+    dx
+}
+
+/// Closed-form point-to-point alignment (Kabsch/Umeyama without scale).
+///
+/// Returns the transform `T` minimizing `sum |T * source - target|^2` over the
+/// `(source, target)` pairs, identity if there are no correspondences.
+pub fn solve_rigid_transform(
+    correspondences: &[(na::Vector3<f64>, na::Vector3<f64>)],
 ) -> na::Isometry3<f64> {
+    if correspondences.is_empty() {
+        return na::Isometry3::identity();
+    }
+    let n = correspondences.len() as f64;
+    let (source_sum, target_sum) = correspondences.iter().fold(
+        (na::Vector3::zeros(), na::Vector3::zeros()),
+        |(s_acc, t_acc), (s, t)| (s_acc + s, t_acc + t),
+    );
+    let source_centroid = source_sum / n;
+    let target_centroid = target_sum / n;
+
+    let cross_covariance = correspondences
+        .iter()
+        .fold(na::Matrix3::<f64>::zeros(), |acc, (s, t)| {
+            acc + (s - source_centroid) * (t - target_centroid).transpose()
+        });
+    let svd = cross_covariance.svd(true, true);
+    let (u, v_t) = (svd.u.unwrap(), svd.v_t.unwrap());
+    // flip the weakest axis if the solution is a reflection
+    let d = (v_t.transpose() * u.transpose()).determinant().signum();
+    let rotation_matrix = v_t.transpose()
+        * na::Matrix3::from_diagonal(&na::Vector3::new(1.0, 1.0, d))
+        * u.transpose();
+    let rotation = na::UnitQuaternion::from_rotation_matrix(&na::Rotation3::from_matrix_unchecked(
+        rotation_matrix,
+    ));
+    let translation = target_centroid - rotation * source_centroid;
+    na::Isometry3::from_parts(translation.into(), rotation)
+}
+
+/// Result of a registration stopped by `error`, holding the initial guess.
+pub(crate) fn aborted_registration(
+    initial_guess: &na::Isometry3<f64>,
+    num_iterations: u16,
+    num_correspondences: usize,
+    num_points: usize,
+    error: RegistrationError,
+) -> IcpResult {
+    IcpResult {
+        pose: *initial_guess,
+        num_iterations,
+        num_correspondences,
+        inlier_ratio: num_correspondences as f64 / num_points.max(1) as f64,
+        converged: false,
+        covariance: None,
+        rejection: Some(ScanRejection::Registration(error)),
+        stats: None,
+        scan_end: None,
+        deskew: None,
+    }
+}
+
+fn align_points_to_map<P: PointLike>(
+    point_cloud: &[P],
+    voxel_map: &voxel_hash_map::VoxelHashMap<P>,
+    initial_guess: &na::Isometry3<f64>,
+    sigma: f64,
+    config: &config::Config,
+    workspace: &mut IcpWorkspace<P>,
+    mut on_iter: impl FnMut(IterationInfo),
+) -> IcpResult {
     if voxel_map.is_empty() {
-        return initial_guess.to_owned();
+        return IcpResult {
+            pose: initial_guess.to_owned(),
+            num_iterations: 0,
+            num_correspondences: 0,
+            inlier_ratio: 0.0,
+            converged: false,
+            covariance: None,
+            rejection: None,
+            stats: None,
+            scan_end: None,
+            deskew: None,
+        };
     }
 
-    let mut source = point_cloud.to_owned();
-    transform_points(initial_guess, &mut source);
+    // correspondences are gated at 3 sigma, the kernel scale defaults to sigma / 3
+    let max_distance = 3.0 * sigma;
+    let kernel_scale = config.robust_kernel_scale.unwrap_or(sigma / 3.0);
+    let IcpWorkspace {
+        source,
+        correspondences,
+        normals,
+        color_gradients,
+        distances,
+        association,
+    } = workspace;
+    source.clear();
+    source.extend_from_slice(point_cloud);
+    transform_points(initial_guess, source);
+
+    let range_image = match config.data_association {
+        config::DataAssociation::RangeImage(params) => {
+            Some(RangeImageMatcher::new(params, voxel_map, initial_guess))
+        }
+        config::DataAssociation::VoxelSearch => None,
+    };
+    let find_neighbor = |pt: &P| match &range_image {
+        Some(matcher) => matcher.get_closest_neighbor(pt),
+        None if config.intensity_weight > 0.0 => voxel_map.closest_neighbor_with_intensity(
+            pt,
+            config.intensity_weight,
+            config.search_voxel_radius as i32,
+            max_distance,
+        ),
+        // gated at the source, farther points are rejected by the association anyway
+        None => voxel_map.closest_neighbor(pt, config.search_voxel_radius as i32, max_distance),
+    };
 
     let mut t_icp = na::Isometry3::<f64>::identity();
     let mut converge_flag = false;
-    for i in 0..max_num_iterations {
-        let correspondences = point_association(&source, voxel_map, max_distance);
-        let (jtj, jtr) = build_linear_system(&correspondences, kernel_scale);
-        let dx = match jtj.qr().solve(&(-jtr)) {
-            Some(dx) => dx,
-            None => {
-                eprintln!("i {} cor {} k {}", i, correspondences.len(), kernel_scale);
-                eprintln!("jtj {}", jtj);
-                eprintln!("jtr {}", jtr);
-                converge_flag = false;
-                break;
+    let mut num_iterations = 0;
+    let mut num_correspondences = 0;
+    let mut covariance = None;
+    for i in 0..config.max_num_iterations {
+        num_iterations = i + 1;
+        association.point_association(
+            config.max_num_threads,
+            source,
+            &find_neighbor,
+            max_distance,
+            config.max_intensity_diff,
+            correspondences,
+        );
+        num_correspondences = correspondences.len();
+        if let Err(error) = check_correspondence_count(num_correspondences, config) {
+            return aborted_registration(
+                initial_guess,
+                num_iterations,
+                num_correspondences,
+                source.len(),
+                error,
+            );
+        }
+        let use_normals = config.registration_mode == config::RegistrationMode::PointToPlane;
+        if use_normals {
+            correspondence_normals(correspondences, source, voxel_map, config, normals);
+        }
+        let use_color = config.color_weight > 0.0;
+        if use_color {
+            correspondence_color_gradients(
+                correspondences,
+                voxel_map,
+                config.voxel_size as f64,
+                color_gradients,
+            );
+        }
+        let step = solve_step(
+            correspondences,
+            use_normals.then_some(normals.as_slice()),
+            use_color.then_some(color_gradients.as_slice()),
+            kernel_scale,
+            config,
+        );
+        let (dx, step_covariance) = match step {
+            Ok(step) => step,
+            Err(error) => {
+                return aborted_registration(
+                    initial_guess,
+                    num_iterations,
+                    num_correspondences,
+                    source.len(),
+                    error,
+                )
             }
         };
+        covariance = step_covariance;
         let estimation = dx.exp();
-        transform_points(&estimation, &mut source);
+        transform_points(&estimation, source);
         t_icp = estimation * t_icp;
-        if dx.norm() < convergence_criterion {
+        let squared_distances: f64 = correspondences
+            .iter()
+            .map(|(s, t)| (s.position() - t.position()).norm_squared())
+            .sum();
+        on_iter(IterationInfo {
+            iteration: i,
+            translation_delta: dx.fixed_rows::<3>(0).norm(),
+            rotation_delta: dx.fixed_rows::<3>(3).norm(),
+            rmse: (squared_distances / correspondences.len().max(1) as f64).sqrt(),
+            num_correspondences,
+        });
+        if config.converged(&dx) {
             converge_flag = true;
             break;
         }
@@ -254,5 +1069,541 @@ fn align_points_to_map(
     if !converge_flag {
         eprintln!("Point cloud could not be aligned to map: could not converge.");
     }
-    t_icp * initial_guess
+    distances.clear();
+    for_each_association(
+        source,
+        find_neighbor,
+        max_distance,
+        config.max_intensity_diff,
+        |_, _, distance| distances.push(distance),
+    );
+    IcpResult {
+        pose: t_icp * initial_guess,
+        num_iterations,
+        num_correspondences,
+        inlier_ratio: if source.is_empty() {
+            0.0
+        } else {
+            num_correspondences as f64 / source.len() as f64
+        },
+        converged: converge_flag,
+        covariance,
+        rejection: None,
+        stats: Some(RegistrationStats::from_distances(distances, source.len())),
+        scan_end: None,
+        deskew: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point3d::Point3d;
+
+    #[test]
+    fn weight_floor_keeps_outlier_system_invertible() {
+        // every residual is beyond the Tukey scale, the pairs of each point cancel out
+        let mut correspondences = Vec::new();
+        for i in 0..5 {
+            for j in 0..5 {
+                for k in 0..2 {
+                    let s = Point3d::new(i as f32, j as f32, 3.0 * k as f32, 1.0);
+                    for d in [Vector3::x(), Vector3::y(), Vector3::z()] {
+                        correspondences.push((s, s.with_position(&(s.position() + d))));
+                        correspondences.push((s, s.with_position(&(s.position() - d))));
+                    }
+                }
+            }
+        }
+        let config = config::Config {
+            robust_kernel: config::RobustKernel::Tukey,
+            min_weight: 1e-3,
+            ..config::Config::default_values()
+        };
+        let system = build_linear_system(&correspondences, None, None, 0.5, &config);
+        let dx = system.jtj.qr().solve(&(-system.jtr)).unwrap();
+        assert!(dx.iter().all(|v| v.is_finite()));
+        assert!(dx.norm() < 1e-9);
+
+        let unfloored = config::Config {
+            min_weight: 0.0,
+            ..config
+        };
+        let system = build_linear_system(&correspondences, None, None, 0.5, &unfloored);
+        assert!(system.jtj.try_inverse().is_none());
+    }
+
+    #[test]
+    fn rigid_transform_is_recovered_from_exact_correspondences() {
+        let transform =
+            na::Isometry3::new(Vector3::new(1.0, -2.0, 0.5), Vector3::new(0.3, -0.2, 1.1));
+        let correspondences: Vec<_> = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [0.0, 2.0, 0.0],
+            [0.0, 0.0, 3.0],
+            [1.0, 1.0, 1.0],
+        ]
+        .iter()
+        .map(|p| {
+            let source = Vector3::from(*p);
+            (source, transform.transform_point(&source.into()).coords)
+        })
+        .collect();
+        let solved = solve_rigid_transform(&correspondences);
+        assert!((solved.inverse() * transform).to_homogeneous().relative_eq(
+            &na::Matrix4::identity(),
+            1e-9,
+            1e-9
+        ));
+    }
+
+    fn grid_scan(n: usize) -> Vec<Point3d> {
+        (0..n)
+            .map(|i| {
+                Point3d::new(
+                    (2 + i % 10) as f32,
+                    (2 + i / 10 % 10) as f32,
+                    (2 + i / 100) as f32,
+                    1.0,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn scan_with_ten_times_fewer_points_is_flagged() {
+        let mut pipeline = IcpPipeline::new_with_config(config::Config {
+            max_point_count_ratio: Some(3.0),
+            ..config::Config::default_values()
+        });
+        assert!(pipeline
+            .process_frame(&mut grid_scan(1000), 0.0, &[])
+            .rejection
+            .is_none());
+        let result = pipeline.process_frame(&mut grid_scan(100), 0.0, &[]);
+        assert_eq!(
+            result.rejection,
+            Some(ScanRejection::PointCountJump {
+                count: 100,
+                average: 1000.0
+            })
+        );
+    }
+
+    #[test]
+    fn lasting_point_count_change_is_adopted() {
+        let mut pipeline = IcpPipeline::new_with_config(config::Config {
+            max_point_count_ratio: Some(3.0),
+            ..config::Config::default_values()
+        });
+        pipeline.process_frame(&mut grid_scan(1000), 0.0, &[]);
+        let rejections = (0..10)
+            .take_while(|_| {
+                pipeline
+                    .process_frame(&mut grid_scan(100), 0.0, &[])
+                    .rejection
+                    .is_some()
+            })
+            .count();
+        assert_eq!(rejections, POINT_COUNT_MAX_REJECTIONS);
+        assert!(pipeline
+            .process_frame(&mut grid_scan(100), 0.0, &[])
+            .rejection
+            .is_none());
+    }
+
+    /// Three orthogonal walls, constraining every degree of freedom.
+    fn corner_scene() -> Vec<Point3d> {
+        let mut points = Vec::new();
+        for i in 0..20 {
+            for j in 0..20 {
+                let (a, b) = (0.5 * i as f32, 0.5 * j as f32);
+                points.push(Point3d::new(0.0, a, b, 1.0));
+                points.push(Point3d::new(a, 0.0, b, 1.0));
+                points.push(Point3d::new(a, b, 0.0, 1.0));
+            }
+        }
+        points
+    }
+
+    fn scene_map(points: &[Point3d]) -> voxel_hash_map::VoxelHashMap {
+        let mut map = voxel_hash_map::VoxelHashMap::default_values();
+        map.update_with_pose(
+            points,
+            &na::Isometry3::identity(),
+            &na::Isometry3::identity(),
+        );
+        map
+    }
+
+    /// `points` seen from `pose`.
+    fn scan_from(points: &[Point3d], pose: &na::Isometry3<f64>) -> Vec<Point3d> {
+        point3d::transform_points(points, &pose.inverse())
+    }
+
+    fn pose_error(a: &na::Isometry3<f64>, b: &na::Isometry3<f64>) -> f64 {
+        (a.inverse() * b).translation.vector.norm() + a.rotation.angle_to(&b.rotation)
+    }
+
+    #[test]
+    fn retry_from_a_good_guess_wins() {
+        let scene = corner_scene();
+        let map = scene_map(&scene);
+        let truth = na::Isometry3::new(Vector3::new(3.0, 2.0, 1.0), Vector3::z() * 0.1);
+        let scan = scan_from(&scene, &truth);
+        let wrong = na::Isometry3::translation(50.0, 0.0, 0.0);
+        let good = na::Isometry3::new(Vector3::new(3.2, 1.9, 1.1), Vector3::z() * 0.12);
+        let config = config::Config::default_values();
+
+        let result = register_scan_with_retry(&scan, &map, &config, &[wrong, good]).unwrap();
+        assert!(result.inlier_ratio >= config.min_inlier_ratio);
+        assert!(pose_error(&result.pose, &truth) < 1e-3);
+    }
+
+    #[test]
+    fn registration_without_correspondences_is_rejected() {
+        let map = scene_map(&corner_scene());
+        let far = na::Isometry3::translation(50.0, 0.0, 0.0);
+        let result = register_scan(
+            &corner_scene(),
+            &map,
+            &config::Config::default_values(),
+            &far,
+        );
+        assert_eq!(
+            result.rejection,
+            Some(ScanRejection::Registration(
+                RegistrationError::SingularSystem
+            ))
+        );
+        assert_eq!(result.pose, far);
+    }
+
+    #[test]
+    fn overlapping_points_are_not_inserted_twice() {
+        let t0 = Instant::now();
+        // stamped evenly over [start_ms, start_ms + 100ms]
+        let stamped = |points: Vec<Point3d>, start_ms: f64| -> Vec<Point3d> {
+            let n = points.len() as f64;
+            points
+                .iter()
+                .enumerate()
+                .map(|(i, pt)| {
+                    let ms = start_ms + 100.0 * i as f64 / n;
+                    pt.with_timestamp(t0 + std::time::Duration::from_secs_f64(ms / 1000.0))
+                })
+                .collect()
+        };
+        let first_scan = stamped(corner_scene(), 0.0);
+        let first_end = first_scan.iter().map(|pt| pt.timestamp()).max().unwrap();
+        let shifted = point3d::transform_points(
+            &corner_scene(),
+            &na::Isometry3::translation(0.25, 0.25, 0.25),
+        );
+        for (policy, inserts_overlap) in [
+            (TimestampOverlapPolicy::Keep, true),
+            (TimestampOverlapPolicy::RegisterOnly, false),
+        ] {
+            let mut pipeline = IcpPipeline::new_with_config(config::Config {
+                timestamp_overlap: policy,
+                ..config::Config::default_values()
+            });
+            pipeline.process_frame(&mut first_scan.clone(), 0.0, &[]);
+            pipeline.process_frame(&mut stamped(shifted.clone(), 50.0), 0.0, &[]);
+            let inserted = pipeline.get_last_batch_points();
+            assert!(inserted.iter().any(|pt| pt.timestamp() > first_end));
+            assert_eq!(
+                inserted.iter().any(|pt| pt.timestamp() <= first_end),
+                inserts_overlap
+            );
+        }
+    }
+
+    #[test]
+    fn every_normal_source_registers() {
+        let scene = corner_scene();
+        let map = scene_map(&scene);
+        let truth = na::Isometry3::new(Vector3::new(3.0, 2.0, 1.0), Vector3::z() * 0.1);
+        // a sparse scan keeps the source normal estimation cheap
+        let sparse: Vec<_> = scene.iter().copied().step_by(4).collect();
+        let scan = scan_from(&sparse, &truth);
+        let guess = na::Isometry3::new(Vector3::new(3.2, 1.9, 1.1), Vector3::z() * 0.12);
+        for normal_source in [
+            config::NormalSource::Target,
+            config::NormalSource::Source,
+            config::NormalSource::Both,
+        ] {
+            let config = config::Config {
+                registration_mode: config::RegistrationMode::PointToPlane,
+                normal_source,
+                ..config::Config::default_values()
+            };
+            let result = register_scan(&scan, &map, &config, &guess);
+            assert!(result.rejection.is_none(), "{normal_source:?}");
+            assert!(pose_error(&result.pose, &truth) < 1e-2, "{normal_source:?}");
+        }
+    }
+
+    #[test]
+    fn target_normals_are_the_map_normals() {
+        let scene = corner_scene();
+        let map = scene_map(&scene);
+        let correspondences: Vec<_> = scene.iter().map(|pt| (*pt, *pt)).collect();
+        let config = config::Config {
+            registration_mode: config::RegistrationMode::PointToPlane,
+            normal_source: config::NormalSource::Target,
+            ..config::Config::default_values()
+        };
+        let mut normals = Vec::new();
+        correspondence_normals(&correspondences, &scene, &map, &config, &mut normals);
+        for ((_, target), normal) in correspondences.iter().zip(&normals) {
+            assert_eq!(*normal, map.estimate_normal(target, None));
+        }
+    }
+
+    #[test]
+    fn low_overlap_scan_is_tracked_but_not_inserted() {
+        let scene = corner_scene();
+        let mut pipeline = IcpPipeline::new_with_config(config::Config {
+            min_overlap_to_insert: 0.8,
+            ..config::Config::default_values()
+        });
+        pipeline.process_frame(&mut scene.clone(), 0.0, &[]);
+        let map_len = pipeline.get_global_map().len();
+
+        // half of the scan is a wall the map has never seen, 20m overhead
+        let truth = na::Isometry3::translation(0.2, 0.0, 0.0);
+        let mut scan = scan_from(&scene, &truth);
+        for i in 0..40 {
+            for j in 0..30 {
+                scan.push(Point3d::new(0.5 * i as f32, 0.5 * j as f32, 20.0, 1.0));
+            }
+        }
+        let result = pipeline.process_frame(&mut scan, 0.0, &[]);
+        assert!(result.rejection.is_none());
+        assert!(pose_error(&result.pose, &truth) < 1e-2);
+        assert_eq!(pipeline.get_global_map().len(), map_len);
+    }
+
+    #[test]
+    fn correspondences_across_intensities_are_rejected() {
+        let target = Point3d::new(1.0, 0.0, 0.0, 100.0);
+        let find_neighbor =
+            |pt: &Point3d| Some((target, (pt.position() - target.position()).norm()));
+        let source = |intensity| Point3d::new(1.1, 0.0, 0.0, intensity);
+        assert!(associate(&source(95.0), &find_neighbor, 1.0, Some(10.0)).is_some());
+        assert!(associate(&source(5.0), &find_neighbor, 1.0, Some(10.0)).is_none());
+        assert!(associate(&source(5.0), &find_neighbor, 1.0, None).is_some());
+    }
+
+    #[test]
+    fn origin_points_are_dropped_regardless_of_min_range() {
+        let is_origin = |pt: &Point3d| pt.position() == Vector3::zeros();
+        // the corner scene holds the origin, its walls meet there
+        assert!(corner_scene().iter().any(is_origin));
+        for drop_origin_points in [true, false] {
+            let mut pipeline = IcpPipeline::new_with_config(config::Config {
+                min_range: 0.0,
+                drop_origin_points,
+                ..config::Config::default_values()
+            });
+            pipeline.process_frame(&mut corner_scene(), 0.0, &[]);
+            assert_eq!(
+                pipeline.get_last_batch_points().iter().any(is_origin),
+                !drop_origin_points
+            );
+        }
+    }
+
+    #[test]
+    fn clamped_steps_stay_in_bounds_and_converge() {
+        let scene = corner_scene();
+        let map = scene_map(&scene);
+        let truth = na::Isometry3::new(Vector3::new(0.3, -0.2, 0.1), Vector3::z() * 0.1);
+        let scan = scan_from(&scene, &truth);
+        let max_step = na::Vector2::new(0.05, 0.01);
+        let config = config::Config {
+            max_step: Some(max_step),
+            ..config::Config::default_values()
+        };
+        let mut steps = Vec::new();
+        let result = align_points_to_map(
+            &scan,
+            &map,
+            &na::Isometry3::identity(),
+            config.initial_threshold,
+            &config,
+            &mut IcpWorkspace::new(),
+            |info| steps.push((info.translation_delta, info.rotation_delta)),
+        );
+        // the first step would overshoot without the clamp
+        assert!((steps[0].0 - max_step.x).abs() < 1e-9);
+        assert!(steps
+            .iter()
+            .all(|(t, r)| *t <= max_step.x + 1e-9 && *r <= max_step.y + 1e-9));
+        assert!(result.converged);
+        assert!(pose_error(&result.pose, &truth) < 1e-3);
+    }
+
+    #[test]
+    fn color_resolves_the_in_plane_translation() {
+        // 2m checkerboard cells on the z = 0 plane
+        let t0 = Instant::now();
+        let board: Vec<Point3d> = (0..32 * 32)
+            .map(|i| {
+                let (x, y) = (0.25 * (i % 32) as f32, 0.25 * (i / 32) as f32);
+                let white = ((x / 2.0) as i32 + (y / 2.0) as i32) % 2 == 0;
+                Point3d::new_with_color(x, y, 0.0, 1.0, t0, [if white { 255 } else { 0 }; 3])
+            })
+            .collect();
+        let map = scene_map(&board);
+        let truth = na::Isometry3::translation(0.1, 0.05, 0.0);
+        let scan = scan_from(&board, &truth);
+        let config = |color_weight| config::Config {
+            registration_mode: config::RegistrationMode::PointToPlane,
+            color_weight,
+            ..config::Config::default_values()
+        };
+
+        // a plane alone leaves x, y and the yaw unobserved
+        let geometric = register_scan(&scan, &map, &config(0.0), &na::Isometry3::identity());
+        assert!(geometric.rejection.is_some() || pose_error(&geometric.pose, &truth) > 0.05);
+
+        let colored = register_scan(&scan, &map, &config(1.0), &na::Isometry3::identity());
+        assert!(colored.rejection.is_none());
+        assert!(pose_error(&colored.pose, &truth) < 0.02, "{}", colored.pose);
+    }
+
+    #[test]
+    fn streamed_correspondences_equal_the_collected_ones() {
+        let map = scene_map(&corner_scene());
+        let source = scan_from(
+            &corner_scene(),
+            &na::Isometry3::new(Vector3::new(0.2, 0.1, -0.1), Vector3::z() * 0.05),
+        );
+        let max_distance = 0.3;
+        let mut streamed = Vec::new();
+        for_each_correspondence(&source, &map, max_distance, |s, t, _| {
+            streamed.push((s.position(), t.position()))
+        });
+        let mut collected = Vec::new();
+        AssociationPool::default().point_association(
+            0,
+            &source,
+            &|pt: &Point3d| map.get_closest_neighbor_within(pt, max_distance),
+            max_distance,
+            None,
+            &mut collected,
+        );
+        assert!(!streamed.is_empty() && streamed.len() < source.len());
+        let collected: Vec<_> = collected
+            .iter()
+            .map(|(s, t)| (s.position(), t.position()))
+            .collect();
+        assert_eq!(streamed, collected);
+    }
+
+    #[test]
+    fn reused_workspace_gives_identical_results() {
+        let scene = corner_scene();
+        let map = scene_map(&scene);
+        let config = config::Config::default_values();
+        let mut workspace = IcpWorkspace::new();
+        for (i, yaw) in [0.1, -0.05, 0.02].into_iter().enumerate() {
+            let truth =
+                na::Isometry3::new(Vector3::new(0.2 * i as f64, 0.1, 0.0), Vector3::z() * yaw);
+            // a different scan size each time, the buffers hold leftovers
+            let scan: Vec<_> = scan_from(&scene, &truth)
+                .into_iter()
+                .step_by(i + 1)
+                .collect();
+            let guess = na::Isometry3::identity();
+            let fresh = register_scan(&scan, &map, &config, &guess);
+            let reused = register_scan_with_workspace(&scan, &map, &config, &guess, &mut workspace);
+            assert_eq!(reused.pose, fresh.pose);
+            assert_eq!(reused.num_iterations, fresh.num_iterations);
+            assert_eq!(reused.num_correspondences, fresh.num_correspondences);
+            assert_eq!(reused.covariance, fresh.covariance);
+        }
+    }
+
+    #[test]
+    fn deskew_outcome_is_reported() {
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + std::time::Duration::from_millis(ms);
+        let scan: Vec<Point3d> = corner_scene()
+            .iter()
+            .enumerate()
+            .map(|(i, pt)| pt.with_timestamp(at(i as u64 % 101)))
+            .collect();
+        // 1 m/s along x
+        let smooth: Vec<_> = (0..=100)
+            .step_by(10)
+            .map(|ms| {
+                (
+                    at(ms),
+                    na::Isometry3::translation(ms as f64 / 1000.0, 0.0, 0.0),
+                )
+            })
+            .collect();
+        let jump = [
+            (at(0), na::Isometry3::identity()),
+            (at(100), na::Isometry3::translation(1.0, 0.0, 0.0)),
+        ];
+        let process = |deskew, poses: &[_]| {
+            let mut pipeline = IcpPipeline::new_with_config(config::Config {
+                deskew,
+                ..config::Config::default_values()
+            });
+            pipeline.process_frame(&mut scan.clone(), 0.0, poses).deskew
+        };
+        assert!(process(false, &smooth).is_none());
+        assert!(matches!(process(true, &smooth), Some(Ok(_))));
+        assert_eq!(process(true, &jump), Some(Err(DeskewError::InvalidPoses)));
+    }
+
+    #[test]
+    fn good_motion_guess_converges_faster() {
+        let scene = corner_scene();
+        let truth = na::Isometry3::new(Vector3::new(0.2, 0.1, 0.05), Vector3::z() * 0.03);
+        let near_truth = na::Isometry3::new(Vector3::new(0.21, 0.1, 0.05), Vector3::z() * 0.03);
+        // identity is the constant velocity prediction after the first scan
+        let mut results = Vec::new();
+        for guess in [na::Isometry3::identity(), near_truth] {
+            let mut pipeline = IcpPipeline::new_with_config(config::Config::default_values());
+            pipeline.process_frame(&mut scene.clone(), 0.0, &[]);
+            let result = pipeline.process_frame_with_motion_guess(
+                &mut scan_from(&scene, &truth),
+                0.0,
+                &[],
+                &guess,
+            );
+            assert!(result.converged);
+            assert!(pose_error(&result.pose, &truth) < 1e-3);
+            results.push(result.num_iterations);
+        }
+        assert!(results[1] < results[0], "{results:?}");
+    }
+
+    #[test]
+    fn nan_inlier_ratio_is_never_the_best() {
+        let result = |inlier_ratio: f64| IcpResult {
+            inlier_ratio,
+            ..aborted_registration(
+                &na::Isometry3::identity(),
+                0,
+                0,
+                0,
+                RegistrationError::SingularSystem,
+            )
+        };
+        let results = [result(0.2), result(f64::NAN), result(0.7), result(0.5)];
+        assert_eq!(select_best(&results).unwrap().inlier_ratio, 0.7);
+        assert!(select_best(&[result(f64::NAN)])
+            .unwrap()
+            .inlier_ratio
+            .is_nan());
+        assert!(select_best(&[]).is_none());
+    }
 }
@@ -0,0 +1,442 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use nalgebra::{Isometry3, Quaternion, Translation3, UnitQuaternion};
+
+use crate::point3d::{Point3d, PointLike};
+
+/// Writes a trajectory in TUM format, one `timestamp tx ty tz qx qy qz qw` row per pose.
+pub fn write_tum_trajectory<Q: AsRef<Path>>(
+    poses: &[(f64, Isometry3<f64>)],
+    path: Q,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for (timestamp, pose) in poses {
+        let t = &pose.translation.vector;
+        let q = &pose.rotation;
+        writeln!(
+            writer,
+            "{:.9} {} {} {} {} {} {} {}",
+            timestamp, t.x, t.y, t.z, q.i, q.j, q.k, q.w
+        )?;
+    }
+    writer.flush()
+}
+
+/// Reads a TUM format trajectory, skipping empty lines and `#` comments.
+pub fn read_tum_trajectory<Q: AsRef<Path>>(path: Q) -> io::Result<Vec<(f64, Isometry3<f64>)>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut poses = Vec::new();
+    for line in reader.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values = line
+            .split_whitespace()
+            .map(|v| v.parse::<f64>())
+            .collect::<Result<Vec<f64>, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let [timestamp, tx, ty, tz, qx, qy, qz, qw] = values[..] else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected 8 values per TUM row, got {}", values.len()),
+            ));
+        };
+        let rotation = UnitQuaternion::from_quaternion(Quaternion::new(qw, qx, qy, qz));
+        poses.push((
+            timestamp,
+            Isometry3::from_parts(Translation3::new(tx, ty, tz), rotation),
+        ));
+    }
+    Ok(poses)
+}
+
+/// Writes `points` as a binary little endian PLY with float `x`, `y`, `z` and
+/// `intensity` vertex properties, plus uchar `red`, `green`, `blue` if any point has
+/// a color (black for the others).
+pub fn write_ply<Q: AsRef<Path>, P: PointLike>(path: Q, points: &[P]) -> io::Result<()> {
+    let colored = points.iter().any(|p| p.color().is_some());
+    let mut writer = BufWriter::new(File::create(path)?);
+    write!(
+        writer,
+        "ply\nformat binary_little_endian 1.0\nelement vertex {}\n\
+         property float x\nproperty float y\nproperty float z\n\
+         property float intensity\n",
+        points.len()
+    )?;
+    if colored {
+        write!(
+            writer,
+            "property uchar red\nproperty uchar green\nproperty uchar blue\n"
+        )?;
+    }
+    writeln!(writer, "end_header")?;
+    for point in points {
+        let p = point.position();
+        for value in [p.x as f32, p.y as f32, p.z as f32, point.intensity()] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        if colored {
+            let rgb = point.color().unwrap_or_default();
+            writer.write_all(&rgb.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8))?;
+        }
+    }
+    writer.flush()
+}
+
+/// Field of a PCD point record.
+struct PcdField {
+    name: String,
+    size: usize,
+    kind: u8,
+    count: usize,
+}
+
+/// Reads an ascii or binary PCD file. `x`, `y` and `z` are required, `intensity` is
+/// 0 when missing and other fields are ignored. Points are stamped with the current
+/// time.
+pub fn read_pcd<Q: AsRef<Path>>(path: Q) -> io::Result<Vec<Point3d>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut reader = BufReader::new(File::open(path)?);
+
+    let mut names = Vec::new();
+    let mut sizes = Vec::new();
+    let mut kinds = Vec::new();
+    let mut counts = Vec::new();
+    let mut num_points = None;
+    let data = loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(invalid("PCD header without DATA".to_string()));
+        }
+        let mut tokens = line.split_whitespace();
+        let Some(key) = tokens.next() else {
+            continue;
+        };
+        let values: Vec<&str> = tokens.collect();
+        let parse_all = |values: &[&str]| {
+            values
+                .iter()
+                .map(|v| v.parse::<usize>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| invalid(format!("invalid PCD {key}: {e}")))
+        };
+        match key {
+            "FIELDS" => names = values.iter().map(|v| v.to_string()).collect(),
+            "SIZE" => sizes = parse_all(&values)?,
+            "TYPE" => kinds = values.iter().map(|v| v.as_bytes()[0]).collect(),
+            "COUNT" => counts = parse_all(&values)?,
+            "POINTS" => num_points = parse_all(&values)?.first().copied(),
+            "DATA" => break values.first().map(|v| v.to_string()).unwrap_or_default(),
+            _ => {}
+        }
+    };
+    if counts.is_empty() {
+        counts = vec![1; names.len()];
+    }
+    if sizes.len() != names.len() || kinds.len() != names.len() || counts.len() != names.len() {
+        return Err(invalid(
+            "PCD FIELDS, SIZE, TYPE and COUNT differ in length".to_string(),
+        ));
+    }
+    let fields: Vec<PcdField> = names
+        .into_iter()
+        .zip(sizes)
+        .zip(kinds)
+        .zip(counts)
+        .map(|(((name, size), kind), count)| PcdField {
+            name,
+            size,
+            kind,
+            count,
+        })
+        .collect();
+    // index of the first value of a field within a record, in values for ascii data
+    // and in bytes for binary data
+    let offset_of = |name: &str, ascii: bool| {
+        let idx = fields.iter().position(|f| f.name == name)?;
+        Some(
+            fields[..idx]
+                .iter()
+                .map(|f| if ascii { f.count } else { f.size * f.count })
+                .sum::<usize>(),
+        )
+    };
+    let field_of = |name: &str| fields.iter().find(|f| f.name == name);
+    let num_points = num_points.ok_or_else(|| invalid("PCD header without POINTS".to_string()))?;
+
+    // POINTS comes from the file, don't trust it for the allocation
+    let mut points = Vec::with_capacity(num_points.min(PCD_MAX_PREALLOCATED_POINTS));
+    match data.as_str() {
+        "ascii" => {
+            let offsets = ["x", "y", "z"].map(|name| offset_of(name, true));
+            let [Some(x), Some(y), Some(z)] = offsets else {
+                return Err(invalid("PCD without x, y or z field".to_string()));
+            };
+            let intensity = offset_of("intensity", true);
+            for line in reader.lines() {
+                let line = line?;
+                let values: Vec<&str> = line.split_whitespace().collect();
+                if values.is_empty() {
+                    continue;
+                }
+                let value = |idx: usize| {
+                    values
+                        .get(idx)
+                        .ok_or_else(|| invalid(format!("PCD row too short: {line}")))?
+                        .parse::<f32>()
+                        .map_err(|e| invalid(format!("invalid PCD value: {e}")))
+                };
+                let intensity = match intensity {
+                    Some(idx) => value(idx)?,
+                    None => 0.0,
+                };
+                points.push(Point3d::new(value(x)?, value(y)?, value(z)?, intensity));
+            }
+        }
+        "binary" => {
+            let record_size = fields
+                .iter()
+                .try_fold(0usize, |acc, f| {
+                    acc.checked_add(f.size.checked_mul(f.count)?)
+                })
+                .filter(|size| *size > 0)
+                .ok_or_else(|| invalid("invalid PCD record size".to_string()))?;
+            let total = record_size
+                .checked_mul(num_points)
+                .ok_or_else(|| invalid(format!("PCD POINTS {num_points} too large")))?;
+            // read at most what the header announces, a lying header fails here
+            let mut bytes = Vec::new();
+            reader.take(total as u64).read_to_end(&mut bytes)?;
+            if bytes.len() != total {
+                return Err(invalid(format!(
+                    "PCD data holds {} bytes, the header announces {total}",
+                    bytes.len()
+                )));
+            }
+            let decoders = ["x", "y", "z", "intensity"]
+                .map(|name| Some((offset_of(name, false)?, field_of(name)?)));
+            let [Some(x), Some(y), Some(z), intensity] = decoders else {
+                return Err(invalid("PCD without x, y or z field".to_string()));
+            };
+            for record in bytes.chunks_exact(record_size) {
+                let value = |(offset, field): (usize, &PcdField)| {
+                    decode_pcd_value(&record[offset..offset + field.size], field.kind)
+                        .ok_or_else(|| invalid(format!("unsupported PCD field {}", field.name)))
+                };
+                let intensity = match intensity {
+                    Some(decoder) => value(decoder)?,
+                    None => 0.0,
+                };
+                points.push(Point3d::new(value(x)?, value(y)?, value(z)?, intensity));
+            }
+        }
+        other => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unsupported PCD data format {other}"),
+            ))
+        }
+    }
+    Ok(points)
+}
+
+/// Upper bound on the points reserved up front by [`read_pcd`].
+const PCD_MAX_PREALLOCATED_POINTS: usize = 1 << 20;
+
+/// Little endian scalar of PCD type `kind` (F, I or U) spanning `bytes`.
+fn decode_pcd_value(bytes: &[u8], kind: u8) -> Option<f32> {
+    Some(match (kind, bytes.len()) {
+        (b'F', 4) => f32::from_le_bytes(bytes.try_into().ok()?),
+        (b'F', 8) => f64::from_le_bytes(bytes.try_into().ok()?) as f32,
+        (b'I', 1) => i8::from_le_bytes(bytes.try_into().ok()?) as f32,
+        (b'I', 2) => i16::from_le_bytes(bytes.try_into().ok()?) as f32,
+        (b'I', 4) => i32::from_le_bytes(bytes.try_into().ok()?) as f32,
+        (b'U', 1) => bytes[0] as f32,
+        (b'U', 2) => u16::from_le_bytes(bytes.try_into().ok()?) as f32,
+        (b'U', 4) => u32::from_le_bytes(bytes.try_into().ok()?) as f32,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector3;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("simple_icp_{}_{name}", std::process::id()))
+    }
+
+    #[test]
+    fn tum_trajectory_round_trip() {
+        let poses = vec![
+            (1_700_000_000.125, Isometry3::identity()),
+            (
+                1_700_000_000.2,
+                Isometry3::new(Vector3::new(1.5, -2.0, 0.25), Vector3::new(0.1, 0.2, -0.3)),
+            ),
+        ];
+        let path = temp_path("trajectory.tum");
+        write_tum_trajectory(&poses, &path).unwrap();
+        let read = read_tum_trajectory(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.len(), poses.len());
+        for ((t_read, pose_read), (t, pose)) in read.iter().zip(&poses) {
+            assert!((t_read - t).abs() < 1e-6);
+            assert!((pose_read.inverse() * pose).to_homogeneous().relative_eq(
+                &nalgebra::Matrix4::identity(),
+                1e-9,
+                1e-9
+            ));
+        }
+    }
+
+    const ASCII_PCD: &str = "\
+# .PCD v0.7 - Point Cloud Data file format
+VERSION 0.7
+FIELDS x y z rgb intensity
+SIZE 4 4 4 4 4
+TYPE F F F U F
+COUNT 1 1 1 1 1
+WIDTH 3
+HEIGHT 1
+VIEWPOINT 0 0 0 1 0 0 0
+POINTS 3
+DATA ascii
+1.5 -2 0.25 4808703 10
+0 0 0 0 0
+
+-3.5 4 8 255 255.5
+";
+
+    fn pcd_header(fields: &str, size: &str, points: &str) -> Vec<u8> {
+        format!(
+            "VERSION 0.7\nFIELDS {fields}\nSIZE {size}\nTYPE F F F\nCOUNT 1 1 1\n\
+             POINTS {points}\nDATA binary\n"
+        )
+        .into_bytes()
+    }
+
+    fn read_pcd_bytes(name: &str, bytes: &[u8]) -> io::Result<Vec<Point3d>> {
+        let path = temp_path(name);
+        std::fs::write(&path, bytes).unwrap();
+        let points = read_pcd(&path);
+        std::fs::remove_file(&path).unwrap();
+        points
+    }
+
+    #[test]
+    fn ascii_pcd_is_read() {
+        let points = read_pcd_bytes("ascii.pcd", ASCII_PCD.as_bytes()).unwrap();
+        let read: Vec<_> = points
+            .iter()
+            .map(|p| [p.x, p.y, p.z, p.intensity])
+            .collect();
+        assert_eq!(
+            read,
+            vec![
+                [1.5, -2.0, 0.25, 10.0],
+                [0.0, 0.0, 0.0, 0.0],
+                [-3.5, 4.0, 8.0, 255.5]
+            ]
+        );
+    }
+
+    #[test]
+    fn binary_pcd_is_read() {
+        let mut bytes = pcd_header("x y z", "4 4 4", "2");
+        for value in [1.0f32, 2.0, 3.0, -4.0, 5.5, 6.0] {
+            bytes.extend(value.to_le_bytes());
+        }
+        let points = read_pcd_bytes("binary.pcd", &bytes).unwrap();
+        assert_eq!(points.len(), 2);
+        assert_eq!([points[1].x, points[1].y, points[1].z], [-4.0, 5.5, 6.0]);
+    }
+
+    #[test]
+    fn malformed_binary_pcd_is_invalid_data() {
+        for (name, size, num_points) in [
+            ("zero_size.pcd", "0 0 0", "10"),
+            ("overflow.pcd", "4 4 4", &usize::MAX.to_string()),
+            ("truncated.pcd", "4 4 4", "1000000000"),
+        ] {
+            let mut bytes = pcd_header("x y z", size, num_points);
+            bytes.extend([0; 24]);
+            let error = read_pcd_bytes(name, &bytes).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{name}");
+        }
+    }
+
+    /// `[x, y, z, intensity]` and color of a PLY vertex
+    type PlyRecord = ([f32; 4], Option<[u8; 3]>);
+
+    /// Header lines and vertices of a PLY written by [`write_ply`].
+    fn parse_ply(bytes: &[u8]) -> (Vec<String>, Vec<PlyRecord>) {
+        let end = b"end_header\n";
+        let header_len = bytes.windows(end.len()).position(|w| w == end).unwrap() + end.len();
+        let header: Vec<String> = std::str::from_utf8(&bytes[..header_len])
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect();
+        let colored = header.iter().any(|l| l == "property uchar red");
+        let record_size = 16 + if colored { 3 } else { 0 };
+        let records = bytes[header_len..]
+            .chunks_exact(record_size)
+            .map(|record| {
+                let value =
+                    |i: usize| f32::from_le_bytes(record[4 * i..4 * i + 4].try_into().unwrap());
+                (
+                    [value(0), value(1), value(2), value(3)],
+                    colored.then(|| [record[16], record[17], record[18]]),
+                )
+            })
+            .collect();
+        (header, records)
+    }
+
+    #[test]
+    fn ply_round_trip() {
+        let t0 = std::time::Instant::now();
+        let points = [
+            Point3d::new(1.5, -2.0, 0.25, 10.0),
+            Point3d::new_with_color(-3.5, 4.0, 8.0, 0.5, t0, [255, 0, 128]),
+        ];
+        let path = temp_path("cloud.ply");
+        write_ply(&path, &points).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (header, records) = parse_ply(&bytes);
+        assert_eq!(header[0], "ply");
+        assert!(header.contains(&"element vertex 2".to_string()));
+        assert_eq!(
+            records,
+            vec![
+                ([1.5, -2.0, 0.25, 10.0], Some([0, 0, 0])),
+                ([-3.5, 4.0, 8.0, 0.5], Some([255, 0, 128])),
+            ]
+        );
+
+        // no color properties without colored points
+        write_ply(&path, &points[..1]).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (header, records) = parse_ply(&bytes);
+        assert!(!header.iter().any(|l| l.contains("red")));
+        assert_eq!(records, vec![([1.5, -2.0, 0.25, 10.0], None)]);
+
+        // the map export goes through the same writer
+        let mut map = crate::voxel_hash_map::VoxelHashMap::default_values();
+        map.update_with_pose(&points[..1], &Isometry3::identity(), &Isometry3::identity());
+        map.to_ply(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(parse_ply(&bytes).1, records);
+    }
+}
@@ -0,0 +1,124 @@
+use nalgebra as na;
+
+use crate::{
+    point3d::{Point3d, PointLike},
+    voxel_hash_map::VoxelHashMap,
+};
+
+/// Collects registered scans into a keyframe that is inserted into the map at once,
+/// so the map maintenance and dedup run once per keyframe instead of per scan.
+pub struct KeyframeAccumulator<P = Point3d> {
+    max_scans: usize,
+    max_translation: f64,
+    max_rotation: f64,
+    // accumulated points in map frame
+    points: Vec<P>,
+    num_scans: usize,
+    first_pose: Option<na::Isometry3<f64>>,
+    last_pose: na::Isometry3<f64>,
+}
+
+impl<P: PointLike> KeyframeAccumulator<P> {
+    /// A keyframe is due after `max_scans` scans or once the sensor moved more than
+    /// `max_translation` (meters) or `max_rotation` (radians) since its first scan.
+    pub fn new(max_scans: usize, max_translation: f64, max_rotation: f64) -> Self {
+        KeyframeAccumulator {
+            max_scans,
+            max_translation,
+            max_rotation,
+            points: Vec::new(),
+            num_scans: 0,
+            first_pose: None,
+            last_pose: na::Isometry3::identity(),
+        }
+    }
+
+    /// Adds a registered scan in sensor frame, returns whether the keyframe should be flushed.
+    pub fn add_scan(&mut self, scan: &[P], t_origin_current: &na::Isometry3<f64>) -> bool {
+        self.points.extend(scan.iter().map(|pt| {
+            pt.with_position(
+                &t_origin_current
+                    .transform_point(&pt.position().into())
+                    .coords,
+            )
+        }));
+        self.num_scans += 1;
+        self.last_pose = *t_origin_current;
+        let first_pose = *self.first_pose.get_or_insert(*t_origin_current);
+        self.is_due(&first_pose)
+    }
+
+    fn is_due(&self, first_pose: &na::Isometry3<f64>) -> bool {
+        let motion = first_pose.inverse() * self.last_pose;
+        self.num_scans >= self.max_scans
+            || motion.translation.vector.norm() > self.max_translation
+            || motion.rotation.angle() > self.max_rotation
+    }
+
+    pub fn num_scans(&self) -> usize {
+        self.num_scans
+    }
+
+    /// Accumulated points in map frame.
+    pub fn points(&self) -> &[P] {
+        &self.points
+    }
+
+    /// Inserts the keyframe into `voxel_map` from the pose of its last scan and starts
+    /// a new one.
+    pub fn flush_into(&mut self, voxel_map: &mut VoxelHashMap<P>) {
+        if self.num_scans == 0 {
+            return;
+        }
+        let last_from_map = self.last_pose.inverse();
+        let keyframe: Vec<P> = self
+            .points
+            .drain(..)
+            .map(|pt| {
+                pt.with_position(&last_from_map.transform_point(&pt.position().into()).coords)
+            })
+            .collect();
+        voxel_map.update_with_pose(&keyframe, &self.last_pose, &na::Isometry3::identity());
+        self.num_scans = 0;
+        self.first_pose = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flushed_keyframe_equals_the_combined_cloud() {
+        let scan: Vec<Point3d> = (0..50)
+            .map(|i| Point3d::new((i % 10) as f32, (i / 10) as f32, 1.0, 0.0))
+            .collect();
+        let poses = [
+            na::Isometry3::identity(),
+            na::Isometry3::new(na::Vector3::new(0.5, 0.3, 0.0), na::Vector3::z() * 0.2),
+        ];
+        let mut accumulator = KeyframeAccumulator::new(2, 10.0, 1.0);
+        assert!(!accumulator.add_scan(&scan, &poses[0]));
+        assert!(accumulator.add_scan(&scan, &poses[1]));
+        let mut accumulated = VoxelHashMap::default_values();
+        accumulator.flush_into(&mut accumulated);
+        assert_eq!(accumulator.num_scans(), 0);
+
+        let combined: Vec<Point3d> = poses
+            .iter()
+            .flat_map(|pose| crate::point3d::transform_points(&scan, pose))
+            .collect();
+        let mut direct = VoxelHashMap::default_values();
+        direct.update_with_pose(
+            &combined,
+            &na::Isometry3::identity(),
+            &na::Isometry3::identity(),
+        );
+        let (accumulated, direct) = (accumulated.get_na_points(), direct.get_na_points());
+        assert_eq!(accumulated.len(), direct.len());
+        // same points up to the f32 round trip through the last pose
+        for a in &accumulated {
+            assert!(direct.iter().any(|d| (a - d).norm() < 1e-4));
+        }
+    }
+}
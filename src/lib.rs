@@ -1,8 +1,14 @@
 pub mod adaptive_threshold;
 pub mod config;
 pub mod deskew;
+pub mod error;
 pub mod icp_pipeline;
+pub mod io;
+pub mod keyframe;
 pub mod lie_group;
+pub mod plane_map;
 pub mod point3d;
+pub mod range_image;
+pub mod segmentation;
 pub mod voxel_hash_map;
 pub mod voxel_util;
@@ -3,6 +3,30 @@ use nalgebra::{
     Vector6, U3,
 };
 
+/// SE3 exponential of the twist `xi = [translation x y z, rotation x y z]`.
+#[inline]
+pub fn se3_exp(xi: &Vector6<f64>) -> Isometry3<f64> {
+    xi.exp()
+}
+
+/// SE3 logarithm, the twist ordered as in [`se3_exp`].
+#[inline]
+pub fn se3_log(pose: &Isometry3<f64>) -> Vector6<f64> {
+    pose.log()
+}
+
+/// SO3 exponential of the rotation vector `omega` (axis times angle).
+#[inline]
+pub fn so3_exp(omega: &Vector3<f64>) -> UnitQuaternion<f64> {
+    omega.exp_and_theta().0
+}
+
+/// SO3 logarithm, the rotation vector with an angle in [0, pi].
+#[inline]
+pub fn so3_log(rotation: &UnitQuaternion<f64>) -> Vector3<f64> {
+    rotation.log_and_theta().0
+}
+
 pub trait Exp<T> {
     fn exp(&self) -> Isometry3<f64>;
 }
@@ -129,6 +153,27 @@ impl LogAndTheta<f64> for UnitQuaternion<f64> {
     }
 }
 
+/// Mean of poses on SE3, iterating log/exp averaging in the tangent space
+/// of the current estimate. Returns identity for an empty slice.
+pub fn average_poses(poses: &[Isometry3<f64>]) -> Isometry3<f64> {
+    let Some(first) = poses.first() else {
+        return Isometry3::identity();
+    };
+    let mut mean = *first;
+    for _ in 0..AVERAGE_MAX_ITERATIONS {
+        let mean_inv = mean.inverse();
+        let delta = poses
+            .iter()
+            .fold(Vector6::zeros(), |acc, pose| acc + (mean_inv * pose).log())
+            / poses.len() as f64;
+        mean *= delta.exp();
+        if delta.norm() < EPSILON {
+            break;
+        }
+    }
+    mean
+}
+
 #[inline]
 pub fn left_jacobian(omega: Vector3<f64>, theta: f64) -> Matrix3<f64> {
     let theta_sq = theta * theta;
@@ -160,3 +205,44 @@ pub fn left_jacobian_inverse(omega: Vector3<f64>, theta: f64) -> Matrix3<f64> {
 }
 
 const EPSILON: f64 = 1e-10;
+const AVERAGE_MAX_ITERATIONS: usize = 20;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pose(xi: [f64; 6]) -> Isometry3<f64> {
+        Vector6::from(xi).exp()
+    }
+
+    #[test]
+    fn average_of_identical_poses_is_that_pose() {
+        let p = pose([1.0, -2.0, 0.5, 0.1, 0.2, -0.3]);
+        let mean = average_poses(&[p, p, p]);
+        assert!((mean.inverse() * p).log().norm() < 1e-9);
+    }
+
+    #[test]
+    fn average_of_symmetric_perturbations_is_the_center() {
+        let center = pose([3.0, 1.0, -1.0, 0.0, 0.4, 0.1]);
+        let delta = Vector6::new(0.2, -0.1, 0.3, 0.05, -0.02, 0.04);
+        let poses = [center * delta.exp(), center * (-delta).exp()];
+        let mean = average_poses(&poses);
+        assert!((mean.inverse() * center).log().norm() < 1e-9);
+    }
+
+    #[test]
+    fn log_inverts_exp() {
+        for xi in [
+            [0.0; 6],
+            [1.0, -2.0, 0.5, 0.1, 0.2, -0.3],
+            [-0.3, 0.7, 4.0, 1.2, -0.8, 2.1],
+            [0.1, 0.0, 0.0, 1e-9, 0.0, -1e-9],
+        ] {
+            let xi = Vector6::from(xi);
+            assert!((se3_log(&se3_exp(&xi)) - xi).norm() < 1e-9, "{xi}");
+            let omega = xi.fixed_rows::<3>(3).into_owned();
+            assert!((so3_log(&so3_exp(&omega)) - omega).norm() < 1e-9, "{omega}");
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use nalgebra as na;
+
+use crate::{
+    config::Config,
+    icp_pipeline::{
+        aborted_registration, solve_step, transform_points, IcpResult, RegistrationStats,
+    },
+    lie_group::Exp,
+    point3d::PointLike,
+    voxel_hash_map::{get_adjacent_voxels, VoxelHashMap, VoxelMap},
+    voxel_util::{estimate_surface, na_vec_to_voxel_anisotropic},
+};
+
+/// Planar patch of a voxel.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plane {
+    pub centroid: na::Vector3<f64>,
+    /// Unit normal, sign is arbitrary
+    pub normal: na::Vector3<f64>,
+}
+
+impl Plane {
+    /// Signed distance of `point` along the normal.
+    pub fn distance(&self, point: &na::Vector3<f64>) -> f64 {
+        self.normal.dot(&(point - self.centroid))
+    }
+}
+
+/// Plane primitives extracted from the planar voxels of a `VoxelHashMap`, for
+/// point-to-plane registration in structured environments.
+#[derive(Debug, Clone)]
+pub struct PlaneMap {
+    pub voxel_size: na::Vector3<f32>,
+    pub planes: VoxelMap<Plane>,
+}
+
+impl PlaneMap {
+    /// Keeps voxels with at least 3 points whose surface variation (smallest eigenvalue
+    /// over the eigenvalue sum of the covariance) is below `max_curvature`.
+    pub fn from_voxel_map<P: PointLike>(map: &VoxelHashMap<P>, max_curvature: f64) -> Self {
+        let planes = map
+            .map
+            .iter()
+            .filter_map(|(voxel, points)| {
+                let positions: Vec<_> = points.iter().map(|pt| pt.position()).collect();
+                fit_plane(&positions, max_curvature).map(|plane| (*voxel, plane))
+            })
+            .collect();
+        PlaneMap {
+            voxel_size: map.voxel_size,
+            planes,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.planes.is_empty()
+    }
+
+    /// Plane with the closest centroid in the voxels around `position`.
+    pub fn closest_plane(&self, position: &na::Vector3<f64>) -> Option<&Plane> {
+        let voxel = na_vec_to_voxel_anisotropic(position, &self.voxel_size.cast());
+        get_adjacent_voxels(&voxel, 1, 1)
+            .filter_map(|query_voxel| self.planes.get(&query_voxel))
+            .min_by(|a, b| {
+                (a.centroid - position)
+                    .norm_squared()
+                    .total_cmp(&(b.centroid - position).norm_squared())
+            })
+    }
+
+    /// Registers a scan (in sensor frame) against the planes starting from
+    /// `initial_guess`, minimizing point-to-plane distances. Gating, kernel, step
+    /// clamp and rejections follow `register_scan`.
+    pub fn register<P: PointLike>(
+        &self,
+        scan: &[P],
+        config: &Config,
+        initial_guess: &na::Isometry3<f64>,
+    ) -> IcpResult {
+        let sigma = config.initial_threshold;
+        let max_distance = 3.0 * sigma;
+        let kernel_scale = config.robust_kernel_scale.unwrap_or(sigma / 3.0);
+
+        let mut source = scan.to_vec();
+        transform_points(initial_guess, &mut source);
+        let mut correspondences = Vec::new();
+        let mut normals = Vec::new();
+        let mut t_icp = na::Isometry3::<f64>::identity();
+        let mut converged = false;
+        let mut num_iterations = 0;
+        let mut num_correspondences = 0;
+        let mut covariance = None;
+        for i in 0..config.max_num_iterations {
+            num_iterations = i + 1;
+            // the target is the projection of the point on its plane, the
+            // point-to-plane residual being the distance along the normal
+            correspondences.clear();
+            normals.clear();
+            for pt in &source {
+                let Some((plane, distance)) = self.plane_within(&pt.position(), max_distance)
+                else {
+                    continue;
+                };
+                let target = pt.position() - plane.normal * distance;
+                correspondences.push((*pt, pt.with_position(&target)));
+                normals.push(Some(plane.normal));
+            }
+            num_correspondences = correspondences.len();
+            let (dx, step_covariance) =
+                match solve_step(&correspondences, Some(&normals), None, kernel_scale, config) {
+                    Ok(step) => step,
+                    Err(error) => {
+                        return aborted_registration(
+                            initial_guess,
+                            num_iterations,
+                            num_correspondences,
+                            source.len(),
+                            error,
+                        )
+                    }
+                };
+            covariance = step_covariance;
+            let estimation = dx.exp();
+            transform_points(&estimation, &mut source);
+            t_icp = estimation * t_icp;
+            if config.converged(&dx) {
+                converged = true;
+                break;
+            }
+        }
+        let mut distances: Vec<f64> = source
+            .iter()
+            .filter_map(|pt| self.plane_within(&pt.position(), max_distance))
+            .map(|(_, distance)| distance.abs())
+            .collect();
+        IcpResult {
+            pose: t_icp * initial_guess,
+            num_iterations,
+            num_correspondences,
+            inlier_ratio: if source.is_empty() {
+                0.0
+            } else {
+                num_correspondences as f64 / source.len() as f64
+            },
+            converged,
+            covariance,
+            rejection: None,
+            stats: Some(RegistrationStats::from_distances(
+                &mut distances,
+                source.len(),
+            )),
+            scan_end: None,
+            deskew: None,
+        }
+    }
+
+    /// Closest plane to `position` with the signed distance to it, None when
+    /// farther than `max_distance`.
+    fn plane_within(
+        &self,
+        position: &na::Vector3<f64>,
+        max_distance: f64,
+    ) -> Option<(&Plane, f64)> {
+        let plane = self.closest_plane(position)?;
+        let distance = plane.distance(position);
+        (distance.abs() <= max_distance).then_some((plane, distance))
+    }
+}
+
+fn fit_plane(points: &[na::Vector3<f64>], max_curvature: f64) -> Option<Plane> {
+    let surface = estimate_surface(points)?;
+    (surface.variation <= max_curvature).then_some(Plane {
+        centroid: surface.mean,
+        normal: surface.normal,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::RegistrationError,
+        icp_pipeline::ScanRejection,
+        point3d::{self, Point3d},
+    };
+
+    fn plane_map_of(points: &[Point3d]) -> PlaneMap {
+        let mut map = VoxelHashMap::default_values();
+        map.update_with_pose(
+            points,
+            &na::Isometry3::identity(),
+            &na::Isometry3::identity(),
+        );
+        PlaneMap::from_voxel_map(&map, 0.01)
+    }
+
+    /// Two walls and the floor of a 10 m corner.
+    fn walls_and_floor() -> (Vec<Point3d>, Vec<Point3d>) {
+        let (mut walls, mut floor) = (Vec::new(), Vec::new());
+        for i in 0..20 {
+            for j in 0..20 {
+                let (a, b) = (0.5 * i as f32 + 0.25, 0.5 * j as f32 + 0.25);
+                walls.push(Point3d::new(0.0, a, b, 1.0));
+                walls.push(Point3d::new(a, 0.0, b, 1.0));
+                floor.push(Point3d::new(a, b, 0.0, 1.0));
+            }
+        }
+        (walls, floor)
+    }
+
+    #[test]
+    fn scan_aligns_to_the_wall_planes() {
+        let (walls, floor) = walls_and_floor();
+        let truth = na::Isometry3::new(na::Vector3::new(0.3, -0.2, 0.1), na::Vector3::z() * 0.05);
+        let config = Config::default_values();
+
+        // two walls leave the translation along their intersection unconstrained
+        let scan = point3d::transform_points(&walls, &truth.inverse());
+        let result = plane_map_of(&walls).register(&scan, &config, &na::Isometry3::identity());
+        assert_eq!(
+            result.rejection,
+            Some(ScanRejection::Registration(
+                RegistrationError::SingularSystem
+            ))
+        );
+
+        let scene: Vec<Point3d> = walls.iter().chain(&floor).copied().collect();
+        let scan = point3d::transform_points(&scene, &truth.inverse());
+        let result = plane_map_of(&scene).register(&scan, &config, &na::Isometry3::identity());
+        assert!(result.rejection.is_none());
+        let error = result.pose.inverse() * truth;
+        assert!(error.translation.vector.norm() < 1e-3, "{error}");
+        assert!(error.rotation.angle() < 1e-3, "{error}");
+        let stats = result.stats.unwrap();
+        assert!(stats.inlier_ratio > 0.9 && stats.rmse < 1e-3, "{stats:?}");
+    }
+
+    #[test]
+    fn registration_applies_the_pipeline_guards() {
+        let (walls, floor) = walls_and_floor();
+        let scene: Vec<Point3d> = walls.iter().chain(&floor).copied().collect();
+        let planes = plane_map_of(&scene);
+        let truth = na::Isometry3::translation(0.3, -0.2, 0.1);
+        let scan = point3d::transform_points(&scene, &truth.inverse());
+
+        let config = Config {
+            min_correspondences: Some(scan.len() + 1),
+            ..Config::default_values()
+        };
+        let result = planes.register(&scan, &config, &na::Isometry3::identity());
+        assert!(matches!(
+            result.rejection,
+            Some(ScanRejection::Registration(
+                RegistrationError::InsufficientCorrespondences { .. }
+            ))
+        ));
+        assert_eq!(result.pose, na::Isometry3::identity());
+
+        // a single clamped iteration moves by at most the translation limit
+        let config = Config {
+            max_step: Some(na::Vector2::new(0.05, 0.01)),
+            max_num_iterations: 1,
+            ..Config::default_values()
+        };
+        let result = planes.register(&scan, &config, &na::Isometry3::identity());
+        assert!(result.rejection.is_none());
+        assert!((result.pose.translation.vector.norm() - 0.05).abs() < 1e-3);
+    }
+}
@@ -1,15 +1,46 @@
 use nalgebra as na;
+use serde::{Deserialize, Serialize};
 
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::Instant;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Point3d {
     pub x: f32,
     pub y: f32,
     pub z: f32,
     pub intensity: f32,
     /// Unix timestamp in seconds
+    #[serde(with = "instant_as_age")]
     pub global_timestamp: Instant,
+    /// 8-bit RGB, `None` for sensors without color. Ignored by the geometry.
+    #[serde(default)]
+    pub rgb: Option<[u8; 3]>,
+    /// Laser ring (channel) index, 0 for sensors without rings
+    #[serde(default)]
+    pub ring: u16,
+    /// Return index of multi-return sensors, 0 for the first (or only) return
+    #[serde(default)]
+    pub return_number: u8,
+}
+
+/// `Instant` has no absolute meaning outside the process, so it is stored as the
+/// age in seconds at serialization time and restored relative to `Instant::now()`.
+mod instant_as_age {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::{Duration, Instant};
+
+    pub fn serialize<S: Serializer>(t: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_f64(t.elapsed().as_secs_f64())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+        let age = f64::deserialize(deserializer)?;
+        let now = Instant::now();
+        Ok(Duration::try_from_secs_f64(age)
+            .ok()
+            .and_then(|age| now.checked_sub(age))
+            .unwrap_or(now))
+    }
 }
 
 impl Point3d {
@@ -20,6 +51,9 @@ impl Point3d {
             z,
             intensity,
             global_timestamp: Instant::now(),
+            rgb: None,
+            ring: 0,
+            return_number: 0,
         }
     }
 
@@ -30,6 +64,23 @@ impl Point3d {
             z,
             intensity,
             global_timestamp: timestamp,
+            rgb: None,
+            ring: 0,
+            return_number: 0,
+        }
+    }
+
+    pub fn new_with_color(
+        x: f32,
+        y: f32,
+        z: f32,
+        intensity: f32,
+        timestamp: Instant,
+        rgb: [u8; 3],
+    ) -> Self {
+        Point3d {
+            rgb: Some(rgb),
+            ..Point3d::new_with_timestamp(x, y, z, intensity, timestamp)
         }
     }
 
@@ -38,6 +89,93 @@ impl Point3d {
     }
 }
 
+/// Point types that can be stored in the map and registered, so that richer
+/// user point types don't need a lossy conversion to [`Point3d`]. Shared across
+/// threads by the parallel map update and correspondence search.
+pub trait PointLike: Copy + Send + Sync {
+    fn position(&self) -> na::Vector3<f64>;
+    fn intensity(&self) -> f32;
+    fn timestamp(&self) -> Instant;
+    /// Copy of the point moved to `position`, every other field preserved
+    fn with_position(&self, position: &na::Vector3<f64>) -> Self;
+    /// Copy of the point with `intensity`, every other field preserved
+    fn with_intensity(&self, intensity: f32) -> Self;
+    /// Copy of the point with `timestamp`, every other field preserved
+    fn with_timestamp(&self, timestamp: Instant) -> Self;
+
+    /// Return index of multi-return sensors (0 = first return)
+    fn return_number(&self) -> u8 {
+        0
+    }
+
+    /// RGB in [0, 1], `None` for uncolored point types
+    fn color(&self) -> Option<[f32; 3]> {
+        None
+    }
+
+    /// Laser ring (channel) index of spinning lidars
+    fn ring(&self) -> u16 {
+        0
+    }
+
+    fn age_seconds(&self) -> f64 {
+        self.age_seconds_at(Instant::now())
+    }
+
+    /// Age at `now`, e.g. an injected clock for deterministic aging
+    fn age_seconds_at(&self, now: Instant) -> f64 {
+        now.saturating_duration_since(self.timestamp())
+            .as_secs_f64()
+    }
+}
+
+impl PointLike for Point3d {
+    #[inline]
+    fn position(&self) -> na::Vector3<f64> {
+        self.to_na_vec_f64()
+    }
+    #[inline]
+    fn intensity(&self) -> f32 {
+        self.intensity
+    }
+    #[inline]
+    fn timestamp(&self) -> Instant {
+        self.global_timestamp
+    }
+    #[inline]
+    fn with_position(&self, position: &na::Vector3<f64>) -> Self {
+        Point3d {
+            x: position.x as f32,
+            y: position.y as f32,
+            z: position.z as f32,
+            ..*self
+        }
+    }
+    #[inline]
+    fn with_intensity(&self, intensity: f32) -> Self {
+        Point3d { intensity, ..*self }
+    }
+    #[inline]
+    fn with_timestamp(&self, timestamp: Instant) -> Self {
+        Point3d {
+            global_timestamp: timestamp,
+            ..*self
+        }
+    }
+    #[inline]
+    fn color(&self) -> Option<[f32; 3]> {
+        self.rgb.map(|rgb| rgb.map(|c| c as f32 / 255.0))
+    }
+    #[inline]
+    fn ring(&self) -> u16 {
+        self.ring
+    }
+    #[inline]
+    fn return_number(&self) -> u8 {
+        self.return_number
+    }
+}
+
 impl Point3d {
     pub fn square(&self) -> f32 {
         self.x * self.x + self.y * self.y + self.z * self.z
@@ -50,17 +188,115 @@ impl Point3d {
     }
 }
 
-pub fn clip_point_cloud_by_distance(
-    point_cloud: &[Point3d],
+/// Plain-old-data wire format of [`Point3d`] for zero-copy reinterpretation of byte
+/// buffers, e.g. shared memory. `Point3d` itself can't be `Pod` since `Instant` is
+/// opaque, so the timestamp is carried as Unix time and converted with a clock anchor,
+/// the Unix time of a known `Instant`.
+///
+/// Layout (`repr(C)`, 32 bytes, 8-byte aligned, no padding, native endianness):
+/// `x`, `y`, `z`, `intensity` as `f32` at offsets 0, 4, 8, 12, `timestamp` as `f64`
+/// at offset 16, `ring` as `u16` at offset 24, then one byte each for `rgb` (offsets
+/// 26 to 28), `has_rgb` (29), `return_number` (30) and `reserved` (31).
+#[cfg(feature = "bytemuck")]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RawPoint3d {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub intensity: f32,
+    /// Unix timestamp in seconds
+    pub timestamp: f64,
+    pub ring: u16,
+    pub rgb: [u8; 3],
+    /// 1 if `rgb` holds a color, 0 otherwise
+    pub has_rgb: u8,
+    pub return_number: u8,
+    /// Always 0, pads the record to 32 bytes
+    pub reserved: u8,
+}
+
+// SAFETY: repr(C) with only float and integer fields and no padding (4 * 4 + 8 + 2 +
+// 6 bytes, aligned to 8), every bit pattern is valid.
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Zeroable for RawPoint3d {}
+#[cfg(feature = "bytemuck")]
+unsafe impl bytemuck::Pod for RawPoint3d {}
+
+#[cfg(feature = "bytemuck")]
+impl RawPoint3d {
+    /// Reinterprets `bytes` without copying, failing if the length is not a multiple
+    /// of 32 bytes or the buffer is not 8-byte aligned.
+    pub fn from_bytes(bytes: &[u8]) -> Result<&[RawPoint3d], bytemuck::PodCastError> {
+        bytemuck::try_cast_slice(bytes)
+    }
+
+    pub fn as_bytes(points: &[RawPoint3d]) -> &[u8] {
+        bytemuck::cast_slice(points)
+    }
+
+    pub fn from_point(point: &Point3d, clock_anchor: (Instant, f64)) -> Self {
+        let (anchor_instant, anchor_unix) = clock_anchor;
+        let timestamp = match point
+            .global_timestamp
+            .checked_duration_since(anchor_instant)
+        {
+            Some(elapsed) => anchor_unix + elapsed.as_secs_f64(),
+            None => {
+                anchor_unix
+                    - anchor_instant
+                        .duration_since(point.global_timestamp)
+                        .as_secs_f64()
+            }
+        };
+        RawPoint3d {
+            x: point.x,
+            y: point.y,
+            z: point.z,
+            intensity: point.intensity,
+            timestamp,
+            ring: point.ring,
+            rgb: point.rgb.unwrap_or_default(),
+            has_rgb: point.rgb.is_some() as u8,
+            return_number: point.return_number,
+            reserved: 0,
+        }
+    }
+
+    /// Timestamps not representable as an `Instant` are clamped to the anchor.
+    pub fn to_point(&self, clock_anchor: (Instant, f64)) -> Point3d {
+        let (anchor_instant, anchor_unix) = clock_anchor;
+        let offset = self.timestamp - anchor_unix;
+        let timestamp = std::time::Duration::try_from_secs_f64(offset.abs())
+            .ok()
+            .and_then(|delta| {
+                if offset >= 0.0 {
+                    anchor_instant.checked_add(delta)
+                } else {
+                    anchor_instant.checked_sub(delta)
+                }
+            })
+            .unwrap_or(anchor_instant);
+        Point3d {
+            rgb: (self.has_rgb != 0).then_some(self.rgb),
+            ring: self.ring,
+            return_number: self.return_number,
+            ..Point3d::new_with_timestamp(self.x, self.y, self.z, self.intensity, timestamp)
+        }
+    }
+}
+
+pub fn clip_point_cloud_by_distance<P: PointLike>(
+    point_cloud: &[P],
     min_distance: f32,
     max_distance: f32,
-) -> Vec<Point3d> {
-    let min2 = min_distance * min_distance;
-    let max2 = max_distance * max_distance;
+) -> Vec<P> {
+    let min2 = (min_distance * min_distance) as f64;
+    let max2 = (max_distance * max_distance) as f64;
     point_cloud
         .iter()
         .filter_map(|pt| {
-            let s = pt.square();
+            let s = pt.position().norm_squared();
             if s < min2 || s > max2 {
                 None
             } else {
@@ -70,19 +306,19 @@ pub fn clip_point_cloud_by_distance(
         .collect()
 }
 
-pub fn clip_point_cloud_by_distance_and_intensity(
-    point_cloud: &[Point3d],
+pub fn clip_point_cloud_by_distance_and_intensity<P: PointLike>(
+    point_cloud: &[P],
     min_distance: f32,
     max_distance: f32,
     min_intensity: f32,
-) -> Vec<Point3d> {
-    let min2 = min_distance * min_distance;
-    let max2 = max_distance * max_distance;
+) -> Vec<P> {
+    let min2 = (min_distance * min_distance) as f64;
+    let max2 = (max_distance * max_distance) as f64;
     point_cloud
         .iter()
         .filter_map(|pt| {
-            let s = pt.square();
-            if s < min2 || s > max2 || pt.intensity < min_intensity {
+            let s = pt.position().norm_squared();
+            if s < min2 || s > max2 || pt.intensity() < min_intensity {
                 None
             } else {
                 Some(*pt)
@@ -90,3 +326,272 @@ pub fn clip_point_cloud_by_distance_and_intensity(
         })
         .collect()
 }
+
+/// Keeps the points whose ring is within `[min_ring, max_ring]`, e.g. to drop the
+/// lowest rings hitting the chassis.
+pub fn clip_point_cloud_by_ring<P: PointLike>(
+    point_cloud: &[P],
+    min_ring: u16,
+    max_ring: u16,
+) -> Vec<P> {
+    point_cloud
+        .iter()
+        .filter(|pt| (min_ring..=max_ring).contains(&pt.ring()))
+        .copied()
+        .collect()
+}
+
+/// Keeps the points within an azimuth range `[min_az, max_az]` and an elevation range
+/// `[min_el, max_el]` (radians, sensor frame, azimuth from +x towards +y, elevation
+/// from the xy plane), e.g. to mask the robot body behind the sensor. A `min_az`
+/// greater than `max_az` after wrapping to [-π, π) selects the range crossing ±π.
+pub fn clip_point_cloud_by_fov<P: PointLike>(
+    point_cloud: &[P],
+    min_az: f32,
+    max_az: f32,
+    min_el: f32,
+    max_el: f32,
+) -> Vec<P> {
+    use std::f64::consts::{PI, TAU};
+    let full_turn = (max_az - min_az) as f64 >= TAU;
+    let wrap = |a: f32| (a as f64 + PI).rem_euclid(TAU) - PI;
+    let (min_az, max_az) = (wrap(min_az), wrap(max_az));
+    let (min_el, max_el) = (min_el as f64, max_el as f64);
+    point_cloud
+        .iter()
+        .filter(|pt| {
+            let p = pt.position();
+            let el = p.z.atan2(p.xy().norm());
+            if el < min_el || el > max_el {
+                return false;
+            }
+            let az = p.y.atan2(p.x);
+            full_turn
+                || if min_az <= max_az {
+                    (min_az..=max_az).contains(&az)
+                } else {
+                    az >= min_az || az <= max_az
+                }
+        })
+        .copied()
+        .collect()
+}
+
+/// Drops points at exactly (0, 0, 0), the usual encoding of a missing return,
+/// whatever the minimum range used for clipping.
+pub fn remove_origin_points<P: PointLike>(point_cloud: &[P]) -> Vec<P> {
+    point_cloud
+        .iter()
+        .filter(|pt| pt.position() != na::Vector3::zeros())
+        .copied()
+        .collect()
+}
+
+/// Splits the cloud into the points within `boundary` of the sensor and the others,
+/// e.g. to register the near field first in a coarse-to-fine scheme.
+pub fn split_by_range<P: PointLike>(point_cloud: &[P], boundary: f32) -> (Vec<P>, Vec<P>) {
+    let boundary2 = (boundary * boundary) as f64;
+    point_cloud
+        .iter()
+        .partition(|pt| pt.position().norm_squared() <= boundary2)
+}
+
+/// Whether `m` is a rotation plus a translation (within 1e-6): orthonormal upper-left
+/// 3x3 block without reflection and a `[0, 0, 0, 1]` bottom row.
+pub fn is_rigid(m: &na::Matrix4<f64>) -> bool {
+    let linear = m.fixed_view::<3, 3>(0, 0);
+    (linear.transpose() * linear).relative_eq(&na::Matrix3::identity(), 1e-6, 1e-6)
+        && linear.determinant() > 0.0
+        && m.row(3)
+            .relative_eq(&na::RowVector4::new(0.0, 0.0, 0.0, 1.0), 1e-6, 1e-6)
+}
+
+/// Applies a homogeneous 4x4 transform, scale and shear included, to every point.
+/// Check [`is_rigid`] first when the cloud must not be deformed.
+pub fn transform_points_matrix<P: PointLike>(point_cloud: &[P], m: &na::Matrix4<f64>) -> Vec<P> {
+    point_cloud
+        .iter()
+        .map(|pt| {
+            let transformed = m.transform_point(&pt.position().into());
+            pt.with_position(&transformed.coords)
+        })
+        .collect()
+}
+
+/// Applies `transform` to every point, converting the rotation to a matrix once
+/// instead of rotating every point by the quaternion.
+pub fn transform_points<P: PointLike>(point_cloud: &[P], transform: &na::Isometry3<f64>) -> Vec<P> {
+    let rotation = transform.rotation.to_rotation_matrix().into_inner();
+    let translation = transform.translation.vector;
+    point_cloud
+        .iter()
+        .map(|pt| pt.with_position(&(rotation * pt.position() + translation)))
+        .collect()
+}
+
+/// Moves timestamps from one time reference to another: a point stamped
+/// `from_epoch + dt` ends up stamped `to_epoch + dt`.
+///
+/// Point ages are measured against `Instant::now()`, so `to_epoch` must be on the
+/// local clock, e.g. the instant a replayed recording started playing. The map
+/// points have to be rebased with the same pair of epochs (see
+/// `VoxelHashMap::rebase_timestamps`) for age queries to stay consistent.
+pub fn rebase_timestamps<P: PointLike>(points: &mut [P], from_epoch: Instant, to_epoch: Instant) {
+    points.iter_mut().for_each(|pt| {
+        *pt = pt.with_timestamp(rebase_instant(pt.timestamp(), from_epoch, to_epoch))
+    });
+}
+
+fn rebase_instant(t: Instant, from_epoch: Instant, to_epoch: Instant) -> Instant {
+    let shifted = if t >= from_epoch {
+        to_epoch.checked_add(t - from_epoch)
+    } else {
+        to_epoch.checked_sub(from_epoch - t)
+    };
+    // only fails for instants not representable on this platform
+    shifted.unwrap_or(to_epoch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotation_matrix_matches_isometry() {
+        let rotation = na::Isometry3::rotation(na::Vector3::new(0.4, -0.3, 1.2));
+        let points = [
+            Point3d::new(1.0, 2.0, 3.0, 0.0),
+            Point3d::new(-4.0, 0.5, 2.0, 0.0),
+        ];
+        let by_matrix = transform_points_matrix(&points, &rotation.to_homogeneous());
+        let by_isometry = transform_points(&points, &rotation);
+        for (a, b) in by_matrix.iter().zip(&by_isometry) {
+            assert!((a.position() - b.position()).norm() < 1e-5);
+        }
+    }
+
+    /// A user point type with an extra field the map must carry along.
+    #[derive(Clone, Copy)]
+    struct LabeledPoint {
+        position: na::Vector3<f64>,
+        timestamp: Instant,
+        label: u32,
+    }
+
+    impl PointLike for LabeledPoint {
+        fn position(&self) -> na::Vector3<f64> {
+            self.position
+        }
+        fn intensity(&self) -> f32 {
+            0.0
+        }
+        fn timestamp(&self) -> Instant {
+            self.timestamp
+        }
+        fn with_position(&self, position: &na::Vector3<f64>) -> Self {
+            LabeledPoint {
+                position: *position,
+                ..*self
+            }
+        }
+        fn with_intensity(&self, _: f32) -> Self {
+            *self
+        }
+        fn with_timestamp(&self, timestamp: Instant) -> Self {
+            LabeledPoint { timestamp, ..*self }
+        }
+    }
+
+    #[test]
+    fn map_works_with_point3d_and_custom_points() {
+        fn closest<P: PointLike>(points: &[P], query: &P) -> P {
+            let mut map = crate::voxel_hash_map::VoxelHashMap::<P>::default_values();
+            map.update_with_pose(
+                points,
+                &na::Isometry3::identity(),
+                &na::Isometry3::identity(),
+            );
+            map.get_closest_neighbor(query).unwrap().0
+        }
+        let points = [
+            Point3d::new(1.0, 1.0, 1.0, 0.0),
+            Point3d::new(3.0, 1.0, 1.0, 0.0),
+        ];
+        let query = Point3d::new(2.8, 1.0, 1.0, 0.0);
+        assert_eq!(closest(&points, &query).x, 3.0);
+
+        let labeled = points.map(|pt| LabeledPoint {
+            position: pt.position(),
+            timestamp: pt.timestamp(),
+            label: pt.x as u32,
+        });
+        let query = LabeledPoint {
+            label: 0,
+            ..labeled[0].with_position(&query.position())
+        };
+        assert_eq!(closest(&labeled, &query).label, 3);
+    }
+
+    #[test]
+    fn split_by_range_partitions_at_the_boundary() {
+        let points = [
+            Point3d::new(1.0, 2.0, 0.0, 0.0),
+            Point3d::new(0.0, 0.0, 5.0, 0.0),
+            Point3d::new(-6.0, 8.0, 0.0, 0.0),
+            Point3d::new(3.0, -4.0, 0.0, 0.0),
+        ];
+        let (near, far) = split_by_range(&points, 5.0);
+        assert_eq!(near.len() + far.len(), points.len());
+        assert!(near.iter().all(|pt| pt.position().norm() <= 5.0));
+        assert_eq!(far.len(), 1);
+        assert_eq!(far[0].x, -6.0);
+    }
+
+    #[cfg(feature = "bytemuck")]
+    #[test]
+    fn raw_point_round_trip_keeps_every_field() {
+        assert_eq!(std::mem::size_of::<RawPoint3d>(), 32);
+        let now = Instant::now();
+        let anchor = (now, 1_700_000_000.0);
+        let point = Point3d {
+            ring: 17,
+            return_number: 1,
+            ..Point3d::new_with_color(1.5, -2.0, 0.25, 10.0, now, [255, 0, 128])
+        };
+        let raw = [
+            RawPoint3d::from_point(&point, anchor),
+            RawPoint3d::from_point(
+                &Point3d::new_with_timestamp(0.0, 1.0, 2.0, 3.0, now),
+                anchor,
+            ),
+        ];
+        let bytes = RawPoint3d::as_bytes(&raw).to_vec();
+        // a fresh Vec<u8> may not be 8-byte aligned, copy into an aligned buffer
+        let mut aligned = vec![0u64; bytes.len() / 8];
+        bytemuck::cast_slice_mut::<u64, u8>(&mut aligned).copy_from_slice(&bytes);
+        let decoded = RawPoint3d::from_bytes(bytemuck::cast_slice(&aligned)).unwrap();
+
+        let colored = decoded[0].to_point(anchor);
+        assert_eq!(
+            [colored.x, colored.y, colored.z, colored.intensity],
+            [1.5, -2.0, 0.25, 10.0]
+        );
+        assert_eq!(colored.rgb, Some([255, 0, 128]));
+        assert_eq!((colored.ring, colored.return_number), (17, 1));
+        assert_eq!(colored.global_timestamp, now);
+        assert_eq!(decoded[1].to_point(anchor).rgb, None);
+    }
+
+    #[test]
+    fn only_rotations_and_translations_are_rigid() {
+        let pose = na::Isometry3::new(na::Vector3::new(1.0, -2.0, 3.0), na::Vector3::z() * 0.7);
+        assert!(is_rigid(&pose.to_homogeneous()));
+        assert!(!is_rigid(&na::Matrix4::new_scaling(2.0)));
+        assert!(!is_rigid(&na::Matrix4::from_diagonal(&na::Vector4::new(
+            -1.0, 1.0, 1.0, 1.0
+        ))));
+        let mut shear = na::Matrix4::identity();
+        shear[(0, 1)] = 0.5;
+        assert!(!is_rigid(&shear));
+    }
+}
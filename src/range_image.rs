@@ -0,0 +1,206 @@
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+
+use crate::{point3d::PointLike, voxel_hash_map::VoxelHashMap};
+
+/// Spherical projection of a spinning lidar, columns span the full azimuth and rows
+/// the elevation range of the beams.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RangeImageParams {
+    pub width: usize,
+    pub height: usize,
+    /// Elevation of the lowest and highest beam (in radians)
+    pub min_elevation: f64,
+    pub max_elevation: f64,
+    /// Half size of the pixel window searched around a projected point
+    pub search_window: usize,
+}
+
+impl RangeImageParams {
+    /// Pixel `(row, col)` of a point expressed in the image frame.
+    pub fn project(&self, point: &na::Vector3<f64>) -> Option<(usize, usize)> {
+        let range = point.norm();
+        if range <= f64::EPSILON || self.width == 0 || self.height == 0 {
+            return None;
+        }
+        let azimuth = point.y.atan2(point.x);
+        let elevation = (point.z / range).asin();
+        if elevation < self.min_elevation || elevation > self.max_elevation {
+            return None;
+        }
+        let col = (((azimuth + PI) / (2.0 * PI)) * self.width as f64) as usize % self.width;
+        let row = (((elevation - self.min_elevation) / (self.max_elevation - self.min_elevation))
+            * self.height as f64) as usize;
+        Some((row.min(self.height - 1), col))
+    }
+}
+
+/// Projective data association: the map is rendered into a range image seen from
+/// a pose, and a query is matched against the points of the pixels around its
+/// projection instead of searching the voxel neighborhood.
+pub struct RangeImageMatcher<P> {
+    params: RangeImageParams,
+    map_from_image: na::Isometry3<f64>,
+    image_from_map: na::Isometry3<f64>,
+    // closest map point per pixel, row major
+    pixels: Vec<Option<P>>,
+}
+
+impl<P: PointLike> RangeImageMatcher<P> {
+    /// Renders the map as seen from `map_from_image`, keeping the nearest point per pixel.
+    pub fn new(
+        params: RangeImageParams,
+        voxel_map: &VoxelHashMap<P>,
+        map_from_image: &na::Isometry3<f64>,
+    ) -> Self {
+        let image_from_map = map_from_image.inverse();
+        let mut pixels: Vec<Option<P>> = vec![None; params.width * params.height];
+        let mut ranges = vec![f64::MAX; params.width * params.height];
+        voxel_map
+            .map
+            .values()
+            .flat_map(|v| v.iter())
+            .for_each(|pt| {
+                let in_image = image_from_map.transform_point(&pt.position().into()).coords;
+                if let Some((row, col)) = params.project(&in_image) {
+                    let idx = row * params.width + col;
+                    let range = in_image.norm();
+                    if range < ranges[idx] {
+                        ranges[idx] = range;
+                        pixels[idx] = Some(*pt);
+                    }
+                }
+            });
+        RangeImageMatcher {
+            params,
+            map_from_image: *map_from_image,
+            image_from_map,
+            pixels,
+        }
+    }
+
+    pub fn map_from_image(&self) -> &na::Isometry3<f64> {
+        &self.map_from_image
+    }
+
+    /// Closest map point among the pixels around the projection of `point` (map frame).
+    /// A window wider than the image covers every column once.
+    pub fn get_closest_neighbor(&self, point: &P) -> Option<(P, f64)> {
+        let point_na = point.position();
+        let in_image = self.image_from_map.transform_point(&point_na.into()).coords;
+        // a projection implies a non-empty image
+        let (row, col) = self.params.project(&in_image)?;
+        let w = self.params.search_window;
+        let rows = row.saturating_sub(w)..=row.saturating_add(w).min(self.params.height - 1);
+        let width = self.params.width as isize;
+        let half_columns = w.min(self.params.width / 2) as isize;
+        // an even width would visit the opposite column from both sides
+        let last_column = (2 * half_columns).min(width - 1);
+        rows.flat_map(move |r| {
+            // columns wrap around at +-pi
+            (0..=last_column).map(move |dc| {
+                let c = (col as isize - half_columns + dc).rem_euclid(width);
+                (r, c as usize)
+            })
+        })
+        .filter_map(|(r, c)| self.pixels[r * self.params.width + c])
+        .map(|pt| (pt, (pt.position() - point_na).norm()))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point3d::Point3d;
+
+    #[test]
+    fn correspondences_match_the_voxel_search() {
+        let params = RangeImageParams {
+            width: 90,
+            height: 8,
+            min_elevation: -0.35,
+            max_elevation: 0.35,
+            search_window: 1,
+        };
+        // one point per pixel center on a 10m sphere, as a lidar at the origin sees it
+        let ray = |row: usize, col: usize| {
+            let azimuth = -PI + (col as f64 + 0.5) * 2.0 * PI / params.width as f64;
+            let elevation = params.min_elevation
+                + (row as f64 + 0.5) * (params.max_elevation - params.min_elevation)
+                    / params.height as f64;
+            na::Vector3::new(
+                elevation.cos() * azimuth.cos(),
+                elevation.cos() * azimuth.sin(),
+                elevation.sin(),
+            )
+        };
+        let rays: Vec<_> = (0..params.height)
+            .flat_map(|row| (0..params.width).map(move |col| (row, col)))
+            .map(|(row, col)| ray(row, col))
+            .collect();
+        let scene: Vec<Point3d> = rays
+            .iter()
+            .map(|r| Point3d::new(r.x as f32 * 10.0, r.y as f32 * 10.0, r.z as f32 * 10.0, 0.0))
+            .collect();
+        let mut map = VoxelHashMap::default_values();
+        map.update_with_pose(
+            &scene,
+            &na::Isometry3::identity(),
+            &na::Isometry3::identity(),
+        );
+        assert_eq!(map.iter_points().count(), scene.len());
+
+        let matcher = RangeImageMatcher::new(params, &map, &na::Isometry3::identity());
+        for r in &rays {
+            let query = Point3d::new(
+                r.x as f32 * 10.05,
+                r.y as f32 * 10.05,
+                r.z as f32 * 10.05,
+                0.0,
+            );
+            let (projected, _) = matcher.get_closest_neighbor(&query).unwrap();
+            let (searched, _) = map.get_closest_neighbor(&query).unwrap();
+            assert_eq!(projected.position(), searched.position());
+        }
+    }
+
+    #[test]
+    fn window_wider_than_the_image_wraps_without_panicking() {
+        let mut map = VoxelHashMap::default_values();
+        let scene = [
+            Point3d::new(5.0, 0.5, 0.5, 0.0),
+            Point3d::new(-5.0, 0.5, 0.5, 0.0),
+            Point3d::new(0.5, -5.0, -0.5, 0.0),
+        ];
+        map.update_with_pose(
+            &scene,
+            &na::Isometry3::identity(),
+            &na::Isometry3::identity(),
+        );
+        let query = Point3d::new(-4.0, 1.0, 0.5, 0.0);
+        // the three points fall in distinct columns at these widths
+        for (width, search_window) in [(4, 4), (4, 10), (3, 2), (8, 100), (5, usize::MAX)] {
+            let params = RangeImageParams {
+                width,
+                height: 2,
+                min_elevation: -0.5,
+                max_elevation: 0.5,
+                search_window,
+            };
+            let matcher = RangeImageMatcher::new(params, &map, &na::Isometry3::identity());
+            let (closest, _) = matcher.get_closest_neighbor(&query).unwrap();
+            assert_eq!(closest.x, -5.0, "width {width}, window {search_window}");
+        }
+        let empty = RangeImageParams {
+            width: 0,
+            height: 0,
+            min_elevation: -0.5,
+            max_elevation: 0.5,
+            search_window: 3,
+        };
+        let matcher = RangeImageMatcher::new(empty, &map, &na::Isometry3::identity());
+        assert!(matcher.get_closest_neighbor(&query).is_none());
+    }
+}
@@ -0,0 +1,192 @@
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+use crate::{point3d::PointLike, voxel_util};
+
+/// RANSAC ground plane fit of [`remove_ground`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct GroundRemovalParams {
+    /// Number of plane hypotheses drawn
+    pub max_iterations: usize,
+    /// Points closer than this to the plane (in meters) are ground
+    pub distance_threshold: f64,
+    /// Voxel size of the downsampled set the plane is fitted on, 0 to fit on every point
+    pub voxel_size: f32,
+    /// Expected ground normal in the points frame, e.g. +z for a level sensor. Planes
+    /// tilted more than `max_tilt` from it are rejected.
+    pub expected_up: Option<na::Vector3<f64>>,
+    /// Maximum angle between the plane normal and `expected_up` (in radians)
+    pub max_tilt: f64,
+}
+
+impl Default for GroundRemovalParams {
+    fn default() -> Self {
+        GroundRemovalParams {
+            max_iterations: 100,
+            distance_threshold: 0.2,
+            voxel_size: 0.5,
+            expected_up: Some(na::Vector3::z()),
+            max_tilt: 0.2,
+        }
+    }
+}
+
+/// Fits the dominant plane with RANSAC and removes its inliers. Returns the remaining
+/// points and the plane `(a, b, c, d)` with `a x + b y + c z + d = 0` and a unit
+/// normal (facing `expected_up` when set), or all the points and `None` when no
+/// plane is found. The sampling is seeded, so the same input gives the same plane.
+pub fn remove_ground<P: PointLike>(
+    points: &[P],
+    params: &GroundRemovalParams,
+) -> (Vec<P>, Option<na::Vector4<f64>>) {
+    let samples: Vec<na::Vector3<f64>> = if params.voxel_size > 0.0 {
+        voxel_util::voxel_downsample(points, params.voxel_size)
+            .iter()
+            .map(|pt| pt.position())
+            .collect()
+    } else {
+        points.iter().map(|pt| pt.position()).collect()
+    };
+    let Some(plane) = fit_plane_ransac(&samples, params) else {
+        return (points.to_vec(), None);
+    };
+    let normal = plane.xyz();
+    let non_ground = points
+        .iter()
+        .filter(|pt| (normal.dot(&pt.position()) + plane.w).abs() > params.distance_threshold)
+        .copied()
+        .collect();
+    (non_ground, Some(plane))
+}
+
+fn fit_plane_ransac(
+    samples: &[na::Vector3<f64>],
+    params: &GroundRemovalParams,
+) -> Option<na::Vector4<f64>> {
+    if samples.len() < 3 {
+        return None;
+    }
+    let up = params
+        .expected_up
+        .and_then(|up| up.try_normalize(f64::EPSILON));
+    let min_cos_tilt = params.max_tilt.cos();
+    let mut rng = XorShift64(RANSAC_SEED);
+    let mut best: Option<(usize, na::Vector4<f64>)> = None;
+    for _ in 0..params.max_iterations {
+        let [a, b, c] = [0; 3].map(|_| samples[rng.next_index(samples.len())]);
+        // degenerate (repeated or collinear) triples have no normal
+        let Some(mut normal) = (b - a).cross(&(c - a)).try_normalize(f64::EPSILON) else {
+            continue;
+        };
+        if let Some(up) = up {
+            if normal.dot(&up) < 0.0 {
+                normal = -normal;
+            }
+            if normal.dot(&up) < min_cos_tilt {
+                continue;
+            }
+        }
+        let d = -normal.dot(&a);
+        let inliers = samples
+            .iter()
+            .filter(|p| (normal.dot(p) + d).abs() <= params.distance_threshold)
+            .count();
+        if best.is_none_or(|(best_inliers, _)| inliers > best_inliers) {
+            best = Some((inliers, normal.push(d)));
+        }
+    }
+    best.map(|(_, plane)| plane)
+}
+
+const RANSAC_SEED: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Minimal PRNG for sampling, avoids a `rand` dependency.
+struct XorShift64(u64);
+
+impl XorShift64 {
+    fn next_index(&mut self, len: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % len as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point3d::Point3d;
+
+    /// Height of the tilted ground z = 0.1 x + 0.5.
+    fn ground_height(x: f32) -> f32 {
+        0.1 * x + 0.5
+    }
+
+    /// Points `height` above the tilted ground.
+    fn above_ground(x: f32, y: f32, height: f32) -> Point3d {
+        Point3d::new(x, y, ground_height(x) + height, 1.0)
+    }
+
+    #[test]
+    fn tilted_ground_is_fitted_and_removed() {
+        let mut points: Vec<Point3d> = (0..1600)
+            .map(|i| {
+                let (x, y) = (-5.0 + 0.25 * (i % 40) as f32, -5.0 + 0.25 * (i / 40) as f32);
+                above_ground(x, y, 0.0)
+            })
+            .collect();
+        // within the threshold of the ground, then obstacles
+        let near_ground = [(-3.0, 1.0), (0.0, -2.0), (2.0, 2.0), (4.0, -4.0)];
+        points.extend(near_ground.map(|(x, y)| above_ground(x, y, 0.15)));
+        let obstacles: Vec<Point3d> = near_ground
+            .iter()
+            .map(|(x, y)| above_ground(*x, *y, 0.3))
+            .chain((0..40).map(|i| above_ground(2.5, 2.5, 0.5 + 0.05 * i as f32)))
+            .collect();
+        points.extend_from_slice(&obstacles);
+
+        let (non_ground, plane) = remove_ground(&points, &GroundRemovalParams::default());
+        let plane = plane.unwrap();
+        let expected = na::Vector4::new(-0.1, 0.0, 1.0, -0.5) / 1.01f64.sqrt();
+        assert!((plane - expected).norm() < 1e-5, "{plane}");
+        assert_eq!(non_ground.len(), obstacles.len());
+        for (kept, obstacle) in non_ground.iter().zip(&obstacles) {
+            assert_eq!(kept.position(), obstacle.position());
+        }
+    }
+
+    #[test]
+    fn walls_are_not_ground_when_up_is_expected() {
+        let wall: Vec<Point3d> = (0..400)
+            .map(|i| Point3d::new(3.0, 0.25 * (i % 20) as f32, 0.25 * (i / 20) as f32, 1.0))
+            .collect();
+        let (kept, plane) = remove_ground(&wall, &GroundRemovalParams::default());
+        assert_eq!((kept.len(), plane), (wall.len(), None));
+
+        let any_plane = GroundRemovalParams {
+            expected_up: None,
+            ..GroundRemovalParams::default()
+        };
+        let (kept, plane) = remove_ground(&wall, &any_plane);
+        assert!(kept.is_empty());
+        assert!((plane.unwrap().x.abs() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn too_few_points_have_no_ground() {
+        let params = GroundRemovalParams::default();
+        let (kept, plane) = remove_ground::<Point3d>(&[], &params);
+        assert_eq!((kept.len(), plane), (0, None));
+
+        let two = [
+            Point3d::new(0.0, 0.0, 0.0, 1.0),
+            Point3d::new(1.0, 0.0, 0.0, 1.0),
+        ];
+        let (kept, plane) = remove_ground(&two, &params);
+        assert_eq!((kept.len(), plane), (2, None));
+        // three points downsampled into a single voxel
+        let close = [0.0, 0.1, 0.2].map(|x| Point3d::new(x, x, 0.0, 1.0));
+        let (kept, plane) = remove_ground(&close, &params);
+        assert_eq!((kept.len(), plane), (3, None));
+    }
+}
@@ -1,217 +1,2065 @@
 use nalgebra as na;
-use std::collections::HashMap;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hasher},
+    ops::Deref,
+    time::Instant,
+};
 
 use crate::{
-    point3d::{self},
-    voxel_util::{self, na_vec_to_voxel},
+    point3d::{self, PointLike},
+    voxel_util::{self, na_vec_to_voxel_anisotropic},
 };
 
 pub type Voxel = na::Vector3<i32>;
-type VoxelPoints = Vec<point3d::Point3d>;
+type VoxelPoints<P> = Vec<P>;
+
+/// Spatial hash for voxel keys, `(x * p1) ^ (y * p2) ^ (z * p3)` with large primes
+/// (Teschner et al. 2003), spreads the structured integer keys of a dense map
+/// better and is cheaper than the default SipHash.
+#[derive(Default, Clone, Copy)]
+pub struct VoxelHasher {
+    hash: u64,
+    axis: usize,
+}
+
+const VOXEL_HASH_PRIMES: [u64; 3] = [73_856_093, 19_349_663, 83_492_791];
+
+impl Hasher for VoxelHasher {
+    #[inline]
+    fn write_i32(&mut self, i: i32) {
+        self.hash ^= (i as u32 as u64).wrapping_mul(VOXEL_HASH_PRIMES[self.axis % 3]);
+        self.axis += 1;
+    }
 
-pub struct VoxelHashMap {
-    pub voxel_size: f32,
+    // nalgebra hashes the (constant) matrix shape first, nothing to mix in
+    #[inline]
+    fn write_usize(&mut self, _: usize) {}
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.hash = (self.hash ^ *b as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    #[inline]
+    fn finish(&self) -> u64 {
+        // the xor of products leaves the high bits empty, which hashbrown relies on
+        // (top 7 bits as tag), and the low bits of a product only depend on the low
+        // bits of the key, so fold the well mixed high half into the bucket index bits
+        let hash = self.hash.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        hash ^ (hash >> 32)
+    }
+}
+
+/// Unseeded, so a map built from the same inserts iterates in the same order on
+/// every run (e.g. `get_na_points`), unlike the randomly seeded `RandomState`.
+pub type VoxelBuildHasher = BuildHasherDefault<VoxelHasher>;
+pub type VoxelMap<V> = HashMap<Voxel, V, VoxelBuildHasher>;
+
+/// Handling of an incoming point within the map resolution of a stored one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergePolicy {
+    /// Keep the stored point (its intensity may still be blended)
+    #[default]
+    Reject,
+    /// Move the stored point to the intensity-weighted average of both, smoothing
+    /// repeated observations of a surface
+    WeightedAverage,
+}
+
+/// Handling of an incoming point in a voxel holding `max_points_per_voxel` points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Keep the stored points, the voxel never refreshes
+    #[default]
+    RejectNew,
+    /// Replace the oldest stored point if the incoming one is newer, letting stale
+    /// geometry yield to fresh observations
+    EvictOldest,
+}
+
+pub struct VoxelHashMap<P = point3d::Point3d> {
+    /// Size of the voxels along x, y and z
+    pub voxel_size: na::Vector3<f32>,
+    /// Points farther than this from the sensor (both in map frame) are evicted on
+    /// every update
     pub max_distance: f64,
     pub max_points_per_voxel: usize,
-    pub map: HashMap<na::Vector3<i32>, VoxelPoints>,
-    pub last_batch_points: VoxelPoints,
+    /// Upper bound on the radius (in voxels) of neighborhood searches
+    pub max_adjacent_voxels: i32,
+    pub map: VoxelMap<VoxelPoints<P>>,
+    /// Mean position of the points of each voxel, kept in sync with `map`
+    pub centroids: VoxelMap<na::Vector3<f64>>,
+    /// Component-wise min and max keys of `map`, kept in sync with it
+    pub voxel_bounds: Option<(Voxel, Voxel)>,
+    /// Points actually inserted (map frame) by the most recent update, see
+    /// [`VoxelHashMap::last_batch`]
+    pub last_batch_points: VoxelPoints<P>,
+    /// Keep `last_batch_points` up to date, disable when only building a map
+    pub track_last_batch: bool,
     pub max_point_age_seconds: Option<f64>,
+    /// Restamp points with the insertion time so processing latency doesn't count
+    /// against their age (their capture time is lost)
+    pub age_from_insert: bool,
+    /// Blend factor pulling a stored point's intensity toward a near-duplicate
+    /// observation (None = keep the first observed intensity)
+    pub intensity_blend: Option<f32>,
+    pub merge_policy: MergePolicy,
+    pub eviction: EvictionPolicy,
+    /// Points below this intensity are not inserted (None = keep all)
+    pub min_intensity: Option<f32>,
+    /// Per-axis scale of the offsets (map frame) in the near-duplicate check: below 1
+    /// points farther apart along that axis still count as duplicates, above 1 they
+    /// are kept as distinct sooner (ones = Euclidean)
+    pub dedup_axis_weights: na::Vector3<f64>,
+    /// Global position of the map frame origin, moved by `recenter` so stored
+    /// coordinates stay small (f32) on long traversals
+    pub origin: na::Vector3<f64>,
 }
 
-fn get_adjacent_voxels(voxel: &Voxel, adjacent_voxels: i32) -> Vec<Voxel> {
-    let mut voxel_neighborhood = Vec::<Voxel>::new();
-    for x in voxel.x - adjacent_voxels..voxel.x + adjacent_voxels + 1 {
-        for y in voxel.y - adjacent_voxels..voxel.y + adjacent_voxels + 1 {
-            for z in voxel.z - adjacent_voxels..voxel.z + adjacent_voxels + 1 {
-                voxel_neighborhood.push(Voxel::new(x, y, z));
-            }
-        }
+/// Voxels within `adjacent_voxels` of `voxel` along each axis, that is
+/// `(2 * adjacent_voxels + 1)^3` voxels: the neighborhood grows with the cube of the
+/// radius, so the radius is clamped to `max_adjacent_voxels` (and to 0 from below).
+/// Lazy, so the per-point neighbor searches don't allocate.
+pub(crate) fn get_adjacent_voxels(
+    voxel: &Voxel,
+    adjacent_voxels: i32,
+    max_adjacent_voxels: i32,
+) -> impl Iterator<Item = Voxel> {
+    let adjacent_voxels = adjacent_voxels.min(max_adjacent_voxels).max(0);
+    let voxel = *voxel;
+    let range = move |c: i32| c - adjacent_voxels..c + adjacent_voxels + 1;
+    range(voxel.x).flat_map(move |x| {
+        range(voxel.y).flat_map(move |y| range(voxel.z).map(move |z| Voxel::new(x, y, z)))
+    })
+}
+
+/// Snapshot of the map state, gathered in a single pass.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapStats {
+    pub num_voxels: usize,
+    pub num_points: usize,
+    pub avg_points_per_voxel: f64,
+    pub max_points_per_voxel: usize,
+    /// Ages in seconds, None for an empty map
+    pub oldest_age: Option<f64>,
+    pub newest_age: Option<f64>,
+    /// Min and max corners of the stored points
+    pub bounds: Option<(na::Vector3<f64>, na::Vector3<f64>)>,
+    /// Estimated heap footprint in bytes
+    pub memory_bytes: usize,
+}
+
+/// Changes of one map update, turning a copy of the map before the update into the
+/// map after it with [`VoxelHashMap::apply_delta`]: `removed` voxels are dropped,
+/// `updated` ones replaced and `added` points appended to their voxel.
+#[derive(Debug, Clone)]
+pub struct MapDelta<P = point3d::Point3d> {
+    /// Inserted points (map frame) with their voxel, for voxels whose stored points
+    /// were left untouched
+    pub added: Vec<(Voxel, P)>,
+    /// Full contents of the remaining voxels whose stored points changed (evicted,
+    /// merged, blended or pruned), inserted points included
+    pub updated: Vec<(Voxel, Vec<P>)>,
+    /// Voxels dropped with all their points
+    pub removed: Vec<Voxel>,
+    /// Sensor position (map frame) the range limit was measured from
+    pub sensor_origin: na::Vector3<f64>,
+}
+
+/// Aging and capacity summary of the map, gathered in a single pass.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapHealth {
+    pub num_points: usize,
+    /// Points not older than the queried age
+    pub num_recent_points: usize,
+    /// Age in seconds, None for an empty map
+    pub oldest_age: Option<f64>,
+    pub num_voxels: usize,
+    /// Fraction of voxels holding `max_points_per_voxel` points
+    pub full_voxel_ratio: f64,
+}
+
+/// Parameters of [`VoxelHashMap::insert_into_voxel`], copied out of the map so
+/// voxels can be filled without borrowing it.
+#[derive(Clone, Copy)]
+struct InsertParams {
+    map_resolution: f64,
+    max_points_per_voxel: usize,
+    intensity_blend: Option<f32>,
+    merge_policy: MergePolicy,
+    eviction: EvictionPolicy,
+    min_intensity: Option<f32>,
+    dedup_axis_weights: na::Vector3<f64>,
+}
+
+impl InsertParams {
+    /// Same criterion as `clip_point_cloud_by_distance_and_intensity`.
+    #[inline]
+    fn admits_intensity<P: PointLike>(&self, pt: &P) -> bool {
+        self.min_intensity
+            .is_none_or(|min_intensity| pt.intensity() >= min_intensity)
     }
-    voxel_neighborhood
 }
 
-impl VoxelHashMap {
-    pub fn default_values() -> VoxelHashMap {
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum InsertOutcome {
+    Added,
+    /// Added in place of an evicted point
+    Replaced,
+    /// A stored point was moved
+    Merged,
+    /// Rejected, but the intensity of a stored point was blended toward it
+    Blended,
+    Rejected,
+}
+
+/// Outcome of [`VoxelHashMap::retain_points`].
+#[derive(Default)]
+struct Pruned {
+    num_points: usize,
+    /// Voxels dropped with all their points
+    emptied: Vec<Voxel>,
+    /// Voxels that lost some of their points
+    thinned: Vec<Voxel>,
+}
+
+impl Pruned {
+    fn extend(&mut self, other: Pruned) {
+        self.num_points += other.num_points;
+        self.emptied.extend(other.emptied);
+        self.thinned.extend(other.thinned);
+    }
+}
+
+fn centroid_of<P: PointLike>(points: &[P]) -> na::Vector3<f64> {
+    points
+        .iter()
+        .map(|p| p.position())
+        .sum::<na::Vector3<f64>>()
+        / points.len() as f64
+}
+
+fn voxel_bounds_of<'a>(mut voxels: impl Iterator<Item = &'a Voxel>) -> Option<(Voxel, Voxel)> {
+    let first = voxels.next()?;
+    Some(voxels.fold((*first, *first), |(min, max), v| (min.inf(v), max.sup(v))))
+}
+
+fn grow_voxel_bounds(bounds: Option<(Voxel, Voxel)>, voxel: &Voxel) -> Option<(Voxel, Voxel)> {
+    Some(match bounds {
+        Some((min, max)) => (min.inf(voxel), max.sup(voxel)),
+        None => (*voxel, *voxel),
+    })
+}
+
+impl<P: PointLike> VoxelHashMap<P> {
+    pub fn default_values() -> VoxelHashMap<P> {
         VoxelHashMap {
-            voxel_size: 1.0,
+            voxel_size: na::Vector3::repeat(1.0),
             max_distance: 100.0,
             max_points_per_voxel: 20,
-            map: HashMap::new(),
+            max_adjacent_voxels: 8,
+            map: VoxelMap::default(),
+            centroids: VoxelMap::default(),
+            voxel_bounds: None,
             last_batch_points: Vec::new(),
+            track_last_batch: true,
             max_point_age_seconds: Some(30.0),
+            age_from_insert: false,
+            intensity_blend: None,
+            merge_policy: MergePolicy::Reject,
+            eviction: EvictionPolicy::RejectNew,
+            min_intensity: None,
+            dedup_axis_weights: na::Vector3::repeat(1.0),
+            origin: na::Vector3::zeros(),
+        }
+    }
+
+    /// Default map with cubic voxels of `voxel_size`.
+    pub fn with_voxel_size(voxel_size: f32) -> VoxelHashMap<P> {
+        VoxelHashMap {
+            voxel_size: na::Vector3::repeat(voxel_size),
+            ..VoxelHashMap::default_values()
         }
     }
 
+    /// Smallest voxel dimension, the scale of neighborhoods and point spacing.
+    #[inline]
+    pub fn min_voxel_size(&self) -> f64 {
+        self.voxel_size.min() as f64
+    }
+
+    #[inline]
+    fn voxel_of_position(&self, position: &na::Vector3<f64>) -> Voxel {
+        na_vec_to_voxel_anisotropic(position, &self.voxel_size.cast())
+    }
+
+    /// Consumes the map into a read-only one for localization against a fixed map.
+    pub fn freeze(self) -> FrozenVoxelMap<P> {
+        FrozenVoxelMap { inner: self }
+    }
+
     #[inline]
     pub fn is_empty(&self) -> bool {
         self.map.is_empty()
     }
 
+    /// Removes every point while keeping the allocated capacity and the parameters.
+    /// The origin is kept so poses relative to it stay valid.
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.centroids.clear();
+        self.voxel_bounds = None;
+        self.last_batch_points.clear();
+    }
+
+    /// Reconfigures the map in place, clearing it since stored voxels don't match
+    /// the new voxel size.
+    pub fn reset_params(
+        &mut self,
+        voxel_size: na::Vector3<f32>,
+        max_distance: f64,
+        max_points_per_voxel: usize,
+    ) {
+        self.clear();
+        self.voxel_size = voxel_size;
+        self.max_distance = max_distance;
+        self.max_points_per_voxel = max_points_per_voxel;
+    }
+
+    /// Voxel key of the cell containing `point`.
+    pub fn voxel_of(&self, point: &P) -> Voxel {
+        self.voxel_of_position(&point.position())
+    }
+
+    pub fn points_in_voxel(&self, voxel: &Voxel) -> Option<&[P]> {
+        self.map.get(voxel).map(|v| v.as_slice())
+    }
+
+    /// Whether the voxel containing `p` holds any point.
+    pub fn is_occupied(&self, p: &na::Vector3<f64>) -> bool {
+        self.map.contains_key(&self.voxel_of_position(p))
+    }
+
+    /// Whether `p` lies in an empty voxel inside the bounds of the occupied voxels,
+    /// anything outside these bounds is unknown rather than free.
+    pub fn is_free(&self, p: &na::Vector3<f64>) -> bool {
+        let voxel = self.voxel_of_position(p);
+        if self.map.contains_key(&voxel) {
+            return false;
+        }
+        // nalgebra orders vectors component-wise
+        self.voxel_bounds
+            .is_some_and(|(min, max)| voxel >= min && voxel <= max)
+    }
+
     pub fn map_len(&self) -> usize {
         self.map.iter().fold(0, |acc, (_, v)| acc + v.len())
     }
 
-    fn update(&mut self, points: &VoxelPoints, current_origin: &na::Vector3<f64>) {
+    fn update(&mut self, points: &VoxelPoints<P>, current_origin: &na::Vector3<f64>, now: Instant) {
         self.add_points(points);
-        self.remove_points_too_far(current_origin);
-        if self.max_point_age_seconds.is_some() {
-            self.remove_aged_points();
-        }
+        self.prune(current_origin, now);
+    }
+
+    /// Range and age pruning of an update.
+    fn prune(&mut self, current_origin: &na::Vector3<f64>, now: Instant) -> Pruned {
+        let mut pruned = self.remove_points_too_far(current_origin);
+        pruned.extend(self.remove_aged_points(now));
+        pruned
+    }
+
+    /// Points inserted by the most recent update (map frame), near-duplicates and
+    /// points rejected by full voxels excluded. Empty when `track_last_batch` is off.
+    pub fn last_batch(&self) -> &[P] {
+        &self.last_batch_points
+    }
+
+    /// Borrows every stored point without collecting them.
+    pub fn iter_points(&self) -> impl Iterator<Item = &P> {
+        self.map.values().flat_map(|v| v.iter())
+    }
+
+    /// Borrows every occupied voxel with its points.
+    pub fn iter_voxels(&self) -> impl Iterator<Item = (&Voxel, &[P])> {
+        self.map
+            .iter()
+            .map(|(voxel, points)| (voxel, points.as_slice()))
+    }
+
+    /// Min and max corners of the stored points (map frame), `None` for an empty map.
+    pub fn aabb(&self) -> Option<(na::Vector3<f64>, na::Vector3<f64>)> {
+        self.iter_points()
+            .map(|pt| pt.position())
+            .fold(None, |bounds, p| {
+                Some(match bounds {
+                    Some((min, max)) => (p.inf(&min), p.sup(&max)),
+                    None => (p, p),
+                })
+            })
     }
 
     pub fn get_na_points(&self) -> Vec<na::Vector3<f64>> {
         self.map
             .values()
-            .map(|v| {
-                v.iter()
-                    .map(|p| p.to_na_vec_f64())
-                    .collect::<Vec<na::Vector3<f64>>>()
-            })
-            .reduce(|mut acc, mut e| {
-                acc.append(&mut e);
-                acc
+            .flat_map(|v| v.iter())
+            .map(|p| p.position())
+            .collect()
+    }
+
+    /// Map points expressed in `frame`, e.g. the current sensor pose for
+    /// visualization in the robot frame.
+    pub fn get_na_points_in_frame(&self, frame: &na::Isometry3<f64>) -> Vec<na::Vector3<f64>> {
+        let frame_inv = frame.inverse();
+        self.map
+            .values()
+            .flat_map(|v| v.iter())
+            .map(|p| frame_inv.transform_point(&p.position().into()).coords)
+            .collect()
+    }
+
+    /// One point per occupied voxel, at the mean position with the mean intensity and
+    /// the newest timestamp (other fields from the newest point), a lightweight
+    /// version of the map for publishing.
+    pub fn centroid_cloud(&self) -> Vec<P> {
+        self.map
+            .iter()
+            .filter_map(|(voxel, points)| {
+                let newest = points.iter().max_by_key(|p| p.timestamp())?;
+                let position = self
+                    .centroids
+                    .get(voxel)
+                    .copied()
+                    .unwrap_or_else(|| centroid_of(points));
+                let intensity =
+                    points.iter().map(|p| p.intensity() as f64).sum::<f64>() / points.len() as f64;
+                Some(
+                    newest
+                        .with_position(&position)
+                        .with_intensity(intensity as f32),
+                )
             })
-            .unwrap()
+            .collect()
     }
 
+    /// Writes the map points in the global frame (origin added) as a binary PLY, see
+    /// [`crate::io::write_ply`].
+    pub fn to_ply<Q: AsRef<std::path::Path>>(&self, path: Q) -> std::io::Result<()> {
+        let points: Vec<P> = self
+            .map
+            .values()
+            .flat_map(|v| v.iter())
+            .map(|p| p.with_position(&(p.position() + self.origin)))
+            .collect();
+        crate::io::write_ply(path, &points)
+    }
+
+    /// Inserts `points` given in sensor frame, `sensor_to_body` being the sensor
+    /// extrinsics (identity when the points are already in body frame).
     pub fn update_with_pose(
         &mut self,
-        points: &[point3d::Point3d],
+        points: &[P],
         t_origin_current: &na::Isometry3<f64>,
+        sensor_to_body: &na::Isometry3<f64>,
     ) {
-        let transformed_points: VoxelPoints = points
-            .iter()
-            .map(|pt| {
-                let transformed_na = t_origin_current * pt.to_na_point3_f64();
-                point3d::Point3d {
-                    x: transformed_na.x as f32,
-                    y: transformed_na.y as f32,
-                    z: transformed_na.z as f32,
-                    intensity: pt.intensity,
-                    global_timestamp: pt.global_timestamp, // Preserve original timestamp
-                }
-            })
+        self.update_with_pose_at(points, t_origin_current, sensor_to_body, Instant::now());
+    }
+
+    /// [`VoxelHashMap::update_with_pose`] aging the map against `now` rather than the
+    /// system clock, e.g. the scan time when replaying a log.
+    pub fn update_with_pose_at(
+        &mut self,
+        points: &[P],
+        t_origin_current: &na::Isometry3<f64>,
+        sensor_to_body: &na::Isometry3<f64>,
+        now: Instant,
+    ) {
+        let t_origin_sensor = t_origin_current * sensor_to_body;
+        let transformed_points = self.to_map_frame_points(points, &t_origin_sensor, now);
+        self.update(
+            &transformed_points,
+            &t_origin_sensor.translation.vector,
+            now,
+        );
+    }
+
+    /// [`VoxelHashMap::update_with_pose`] returning the changes of the map, for
+    /// mirroring it remotely without sending the whole map.
+    pub fn update_with_pose_delta(
+        &mut self,
+        points: &[P],
+        t_origin_current: &na::Isometry3<f64>,
+        sensor_to_body: &na::Isometry3<f64>,
+    ) -> MapDelta<P> {
+        self.update_with_pose_delta_at(points, t_origin_current, sensor_to_body, Instant::now())
+    }
+
+    /// [`VoxelHashMap::update_with_pose_delta`] aging the map against `now`.
+    pub fn update_with_pose_delta_at(
+        &mut self,
+        points: &[P],
+        t_origin_current: &na::Isometry3<f64>,
+        sensor_to_body: &na::Isometry3<f64>,
+        now: Instant,
+    ) -> MapDelta<P> {
+        let t_origin_sensor = t_origin_current * sensor_to_body;
+        let sensor_origin = t_origin_sensor.translation.vector;
+        let transformed_points = self.to_map_frame_points(points, &t_origin_sensor, now);
+        let mut inserted = Vec::new();
+        let mut modified = Vec::new();
+        self.insert_points(
+            &transformed_points,
+            Some(&mut inserted),
+            Some(&mut modified),
+        );
+        self.last_batch_points.clear();
+        if self.track_last_batch {
+            self.last_batch_points.extend_from_slice(&inserted);
+        }
+        let pruned = self.prune(&sensor_origin, now);
+        // voxels whose stored points changed are sent whole, the rest only gain points
+        let updated: VoxelMap<()> = modified
+            .into_iter()
+            .chain(pruned.thinned)
+            .filter(|voxel| self.map.contains_key(voxel))
+            .map(|voxel| (voxel, ()))
             .collect();
-        self.update(&transformed_points, &t_origin_current.translation.vector);
-    }
-
-    fn add_points(&mut self, points: &VoxelPoints) {
-        let mut last_batch = Vec::new();
-        let map_resolution =
-            (self.voxel_size * self.voxel_size / self.max_points_per_voxel as f32).sqrt() as f64;
-        points.iter().for_each(|pt| {
-            let voxel = na_vec_to_voxel(&pt.to_na_vec_f64(), self.voxel_size as f64);
-            if let Some(voxel_points) = self.map.get_mut(&voxel) {
-                if voxel_points.len() >= self.max_points_per_voxel
-                    || voxel_points.iter().any(|vpt| {
-                        (vpt.to_na_vec_f64() - pt.to_na_vec_f64()).norm() < map_resolution
-                    })
-                {
-                    // return;
-                } else {
-                    voxel_points.push(*pt);
-                    last_batch.push(*pt);
-                }
-            } else {
-                self.map.insert(voxel, vec![*pt]);
-                last_batch.push(*pt);
+        let added = inserted
+            .into_iter()
+            .map(|pt| (self.voxel_of(&pt), pt))
+            .filter(|(voxel, _)| self.map.contains_key(voxel) && !updated.contains_key(voxel))
+            .collect();
+        let updated = updated
+            .into_keys()
+            .map(|voxel| (voxel, self.map[&voxel].clone()))
+            .collect();
+        MapDelta {
+            added,
+            updated,
+            removed: pruned.emptied,
+            sensor_origin,
+        }
+    }
+
+    /// Applies the changes of an update of another map, see [`MapDelta`]. Both maps
+    /// must share the voxel size and origin.
+    pub fn apply_delta(&mut self, delta: &MapDelta<P>) {
+        for voxel in &delta.removed {
+            self.map.remove(voxel);
+            self.centroids.remove(voxel);
+        }
+        for (voxel, points) in &delta.updated {
+            self.map.insert(*voxel, points.clone());
+        }
+        for (voxel, pt) in &delta.added {
+            self.map.entry(*voxel).or_default().push(*pt);
+        }
+        let touched = delta.updated.iter().map(|(voxel, _)| voxel);
+        for voxel in touched.chain(delta.added.iter().map(|(voxel, _)| voxel)) {
+            if let Some(voxel_points) = self.map.get(voxel) {
+                self.centroids.insert(*voxel, centroid_of(voxel_points));
             }
-        });
+        }
+        self.voxel_bounds = voxel_bounds_of(self.map.keys());
+    }
+
+    fn to_map_frame_points(
+        &self,
+        points: &[P],
+        t_origin_sensor: &na::Isometry3<f64>,
+        now: Instant,
+    ) -> Vec<P> {
+        let mut transformed_points = point3d::transform_points(points, t_origin_sensor);
+        // Preserve original timestamp unless aging from insertion
+        if self.age_from_insert {
+            transformed_points
+                .iter_mut()
+                .for_each(|pt| *pt = pt.with_timestamp(now));
+        }
+        transformed_points
+    }
+
+    fn add_points(&mut self, points: &VoxelPoints<P>) {
+        let mut last_batch = std::mem::take(&mut self.last_batch_points);
+        last_batch.clear();
+        self.insert_points(
+            points,
+            self.track_last_batch.then_some(&mut last_batch),
+            None,
+        );
         self.last_batch_points = last_batch;
     }
-    fn remove_points_too_far(&mut self, current_origin: &na::Vector3<f64>) {
-        let max_distance2 = self.max_distance * self.max_distance;
-        let keys_too_far: Vec<Voxel> = self
-            .map
+
+    /// Inserts `points` (in map frame), collecting the ones actually added into
+    /// `inserted` and the voxels whose stored points changed into `modified`, and
+    /// returns how many were added.
+    fn insert_points(
+        &mut self,
+        points: &[P],
+        mut inserted: Option<&mut Vec<P>>,
+        mut modified: Option<&mut Vec<Voxel>>,
+    ) -> usize {
+        let mut num_inserted = 0;
+        let params = self.insert_params();
+        points
             .iter()
-            .filter_map(|(k, vps)| {
-                if (vps[0].to_na_vec_f64() - current_origin).norm_squared() >= max_distance2 {
-                    Some(k.to_owned())
-                } else {
-                    None
+            .filter(|pt| params.admits_intensity(*pt))
+            .for_each(|pt| {
+                let voxel = self.voxel_of_position(&pt.position());
+                let voxel_points = self.map.entry(voxel).or_default();
+                match Self::insert_into_voxel(voxel_points, pt, &params) {
+                    InsertOutcome::Added => {
+                        self.voxel_bounds = grow_voxel_bounds(self.voxel_bounds, &voxel);
+                        num_inserted += 1;
+                        if let Some(inserted) = inserted.as_mut() {
+                            inserted.push(*pt);
+                        }
+                        // running mean
+                        let n = voxel_points.len() as f64;
+                        let centroid = self.centroids.entry(voxel).or_insert(pt.position());
+                        *centroid += (pt.position() - *centroid) / n;
+                    }
+                    InsertOutcome::Replaced => {
+                        num_inserted += 1;
+                        if let Some(inserted) = inserted.as_mut() {
+                            inserted.push(*pt);
+                        }
+                        if let Some(modified) = modified.as_mut() {
+                            modified.push(voxel);
+                        }
+                        self.centroids.insert(voxel, centroid_of(voxel_points));
+                    }
+                    InsertOutcome::Merged => {
+                        if let Some(modified) = modified.as_mut() {
+                            modified.push(voxel);
+                        }
+                        self.centroids.insert(voxel, centroid_of(voxel_points));
+                    }
+                    InsertOutcome::Blended => {
+                        if let Some(modified) = modified.as_mut() {
+                            modified.push(voxel);
+                        }
+                    }
+                    InsertOutcome::Rejected => {}
                 }
-            })
+            });
+        num_inserted
+    }
+
+    /// Folds the points of `other` into this map through the same dedup and capacity
+    /// rules as scan insertion. Maps of different voxel size or origin are supported,
+    /// the points of `other` being re-voxelized into this map's grid. Aging and range
+    /// limits are not applied. Returns the number of points added.
+    pub fn merge(&mut self, other: &VoxelHashMap<P>) -> usize {
+        let shift = other.origin - self.origin;
+        let points: Vec<P> = other
+            .map
+            .values()
+            .flat_map(|voxel_points| voxel_points.iter())
+            .map(|pt| pt.with_position(&(pt.position() + shift)))
             .collect();
-        keys_too_far.iter().for_each(|k| {
-            self.map.remove(k);
-        });
+        self.insert_points(&points, None, None)
     }
 
-    fn remove_aged_points(&mut self) {
-        if let Some(max_age) = self.max_point_age_seconds {
-            let mut empty_voxels = Vec::new();
+    /// Minimum spacing between the points of a voxel.
+    fn map_resolution(&self) -> f64 {
+        let voxel_size = self.min_voxel_size();
+        (voxel_size * voxel_size / self.max_points_per_voxel as f64).sqrt()
+    }
 
-            for (voxel_key, voxel_points) in self.map.iter_mut() {
-                voxel_points.retain(|point| point.age_seconds() <= max_age);
+    fn insert_params(&self) -> InsertParams {
+        InsertParams {
+            map_resolution: self.map_resolution(),
+            max_points_per_voxel: self.max_points_per_voxel,
+            intensity_blend: self.intensity_blend,
+            merge_policy: self.merge_policy,
+            eviction: self.eviction,
+            min_intensity: self.min_intensity,
+            dedup_axis_weights: self.dedup_axis_weights,
+        }
+    }
 
-                if voxel_points.is_empty() {
-                    empty_voxels.push(*voxel_key);
+    /// Adds `pt` to the points of its voxel unless it is a near-duplicate of a stored
+    /// point (handled by the merge policy instead) or the voxel is full (handled by
+    /// the eviction policy). A new voxel always takes its first point.
+    fn insert_into_voxel(
+        voxel_points: &mut Vec<P>,
+        pt: &P,
+        params: &InsertParams,
+    ) -> InsertOutcome {
+        let duplicate = voxel_points.iter_mut().find(|vpt| {
+            (vpt.position() - pt.position())
+                .component_mul(&params.dedup_axis_weights)
+                .norm()
+                < params.map_resolution
+        });
+        if let Some(duplicate) = duplicate {
+            match params.merge_policy {
+                MergePolicy::Reject => {
+                    let Some(blend) = params.intensity_blend else {
+                        return InsertOutcome::Rejected;
+                    };
+                    let intensity = duplicate.intensity();
+                    *duplicate =
+                        duplicate.with_intensity(intensity + blend * (pt.intensity() - intensity));
+                    InsertOutcome::Blended
+                }
+                MergePolicy::WeightedAverage => {
+                    // zero intensities fall back to the plain mean
+                    let w_stored = duplicate.intensity().max(f32::EPSILON) as f64;
+                    let w_new = pt.intensity().max(f32::EPSILON) as f64;
+                    let total = w_stored + w_new;
+                    let position =
+                        (duplicate.position() * w_stored + pt.position() * w_new) / total;
+                    let intensity = (duplicate.intensity() as f64 * w_stored
+                        + pt.intensity() as f64 * w_new)
+                        / total;
+                    *duplicate = duplicate
+                        .with_position(&position)
+                        .with_intensity(intensity as f32);
+                    InsertOutcome::Merged
                 }
             }
+        } else if !voxel_points.is_empty() && voxel_points.len() >= params.max_points_per_voxel {
+            if params.eviction == EvictionPolicy::RejectNew {
+                return InsertOutcome::Rejected;
+            }
+            match voxel_points.iter_mut().min_by_key(|vpt| vpt.timestamp()) {
+                Some(oldest) if oldest.timestamp() < pt.timestamp() => {
+                    *oldest = *pt;
+                    InsertOutcome::Replaced
+                }
+                _ => InsertOutcome::Rejected,
+            }
+        } else {
+            voxel_points.push(*pt);
+            InsertOutcome::Added
+        }
+    }
+    /// Drops the points farther than `max_distance` from `current_origin`, the sensor
+    /// position in map frame, and the voxels left empty.
+    fn remove_points_too_far(&mut self, current_origin: &na::Vector3<f64>) -> Pruned {
+        let max_distance2 = self.max_distance * self.max_distance;
+        self.retain_points(|pt| (pt.position() - current_origin).norm_squared() < max_distance2)
+    }
+
+    fn remove_aged_points(&mut self, now: Instant) -> Pruned {
+        match self.max_point_age_seconds {
+            Some(max_age) => self.retain_points(|pt| pt.age_seconds_at(now) <= max_age),
+            None => Pruned::default(),
+        }
+    }
+
+    /// Drops the points older than `max_point_age_seconds` at `now`, points exactly
+    /// that old are kept. Updates age the map against the system clock, passing a
+    /// fixed `now` here ages it deterministically.
+    pub fn remove_aged_points_at(&mut self, now: Instant) {
+        self.remove_aged_points(now);
+    }
 
-            for voxel_key in empty_voxels {
-                self.map.remove(&voxel_key);
+    /// Removes the points inside the axis-aligned box `[min, max]` (map frame), e.g.
+    /// around a dynamic obstacle, and returns how many were removed.
+    pub fn remove_points_in_aabb(
+        &mut self,
+        min: &na::Vector3<f64>,
+        max: &na::Vector3<f64>,
+    ) -> usize {
+        self.retain_points(|pt| {
+            let p = pt.position();
+            !(p >= *min && p <= *max)
+        })
+        .num_points
+    }
+
+    /// Keeps the points satisfying `keep`, dropping the voxels left empty.
+    fn retain_points(&mut self, keep: impl Fn(&P) -> bool) -> Pruned {
+        let mut pruned = Pruned::default();
+        for (voxel_key, voxel_points) in self.map.iter_mut() {
+            let len_before = voxel_points.len();
+            voxel_points.retain(&keep);
+            pruned.num_points += len_before - voxel_points.len();
+
+            if voxel_points.is_empty() {
+                pruned.emptied.push(*voxel_key);
+            } else if voxel_points.len() != len_before {
+                pruned.thinned.push(*voxel_key);
+                self.centroids.insert(*voxel_key, centroid_of(voxel_points));
             }
         }
+
+        for voxel_key in &pruned.emptied {
+            self.map.remove(voxel_key);
+            self.centroids.remove(voxel_key);
+        }
+        if !pruned.emptied.is_empty() {
+            self.voxel_bounds = voxel_bounds_of(self.map.keys());
+        }
+        pruned
+    }
+
+    /// [`VoxelHashMap::stats`] as of now, for periodic monitoring of the map size
+    /// and estimated footprint.
+    pub fn memory_usage(&self) -> MapStats {
+        self.stats(Instant::now())
+    }
+
+    /// Counts, ages relative to `now`, bounds and estimated footprint of the map.
+    pub fn stats(&self, now: Instant) -> MapStats {
+        let mut stats = MapStats {
+            num_voxels: self.map.len(),
+            num_points: 0,
+            avg_points_per_voxel: 0.0,
+            max_points_per_voxel: 0,
+            oldest_age: None,
+            newest_age: None,
+            bounds: None,
+            memory_bytes: self.memory_estimate(),
+        };
+        for voxel_points in self.map.values() {
+            stats.num_points += voxel_points.len();
+            stats.max_points_per_voxel = stats.max_points_per_voxel.max(voxel_points.len());
+            for pt in voxel_points {
+                let age = now.saturating_duration_since(pt.timestamp()).as_secs_f64();
+                stats.oldest_age = Some(stats.oldest_age.map_or(age, |a| a.max(age)));
+                stats.newest_age = Some(stats.newest_age.map_or(age, |a| a.min(age)));
+                let p = pt.position();
+                stats.bounds = Some(match stats.bounds {
+                    Some((min, max)) => (min.inf(&p), max.sup(&p)),
+                    None => (p, p),
+                });
+            }
+        }
+        if stats.num_voxels > 0 {
+            stats.avg_points_per_voxel = stats.num_points as f64 / stats.num_voxels as f64;
+        }
+        stats
+    }
+
+    fn memory_estimate(&self) -> usize {
+        use std::mem::size_of;
+        // hashbrown stores one control byte per bucket next to the entry
+        let points_map = self.map.capacity() * (size_of::<Voxel>() + size_of::<Vec<P>>() + 1);
+        let points: usize = self
+            .map
+            .values()
+            .map(|v| v.capacity() * size_of::<P>())
+            .sum();
+        let centroids =
+            self.centroids.capacity() * (size_of::<Voxel>() + size_of::<na::Vector3<f64>>() + 1);
+        let last_batch = self.last_batch_points.capacity() * size_of::<P>();
+        points_map + points + centroids + last_batch
+    }
+
+    /// Rebases every stored timestamp, see [`point3d::rebase_timestamps`].
+    pub fn rebase_timestamps(&mut self, from_epoch: Instant, to_epoch: Instant) {
+        self.map.values_mut().for_each(|voxel_points| {
+            point3d::rebase_timestamps(voxel_points, from_epoch, to_epoch)
+        });
+        point3d::rebase_timestamps(&mut self.last_batch_points, from_epoch, to_epoch);
+    }
+
+    /// Moves the map frame origin to the voxel of `position` (in map frame), shifting
+    /// every stored point by whole voxels so the keys stay aligned. Returns the shift
+    /// to subtract from map-frame coordinates, already added to `origin`.
+    pub fn recenter(&mut self, position: &na::Vector3<f64>) -> na::Vector3<f64> {
+        let voxel_shift = self.voxel_of_position(position);
+        if voxel_shift == Voxel::zeros() {
+            return na::Vector3::zeros();
+        }
+        let shift = voxel_shift
+            .cast::<f64>()
+            .component_mul(&self.voxel_size.cast());
+        let shift_point = |pt: &P| pt.with_position(&(pt.position() - shift));
+        self.map = self
+            .map
+            .drain()
+            .map(|(voxel, points)| {
+                (
+                    voxel - voxel_shift,
+                    points.iter().map(shift_point).collect(),
+                )
+            })
+            .collect();
+        self.centroids = self
+            .centroids
+            .drain()
+            .map(|(voxel, centroid)| (voxel - voxel_shift, centroid - shift))
+            .collect();
+        self.voxel_bounds = self
+            .voxel_bounds
+            .map(|(min, max)| (min - voxel_shift, max - voxel_shift));
+        self.last_batch_points = self.last_batch_points.iter().map(shift_point).collect();
+        self.origin += shift;
+        shift
+    }
+
+    /// Point counts, oldest age and voxel occupancy in one pass, `recent_age_seconds`
+    /// being the age limit of the recent points (as `get_point_count_by_age`).
+    pub fn health(&self, recent_age_seconds: f64) -> MapHealth {
+        self.health_at(recent_age_seconds, Instant::now())
+    }
+
+    /// [`VoxelHashMap::health`] with ages relative to `now`.
+    pub fn health_at(&self, recent_age_seconds: f64, now: Instant) -> MapHealth {
+        let mut health = MapHealth {
+            num_points: 0,
+            num_recent_points: 0,
+            oldest_age: None,
+            num_voxels: self.map.len(),
+            full_voxel_ratio: 0.0,
+        };
+        let mut num_full_voxels = 0;
+        for voxel_points in self.map.values() {
+            health.num_points += voxel_points.len();
+            if voxel_points.len() >= self.max_points_per_voxel {
+                num_full_voxels += 1;
+            }
+            for pt in voxel_points {
+                let age = now.saturating_duration_since(pt.timestamp()).as_secs_f64();
+                if age <= recent_age_seconds {
+                    health.num_recent_points += 1;
+                }
+                health.oldest_age = Some(health.oldest_age.map_or(age, |a| a.max(age)));
+            }
+        }
+        if health.num_voxels > 0 {
+            health.full_voxel_ratio = num_full_voxels as f64 / health.num_voxels as f64;
+        }
+        health
     }
 
     pub fn get_point_count_by_age(&self, max_age_seconds: f64) -> usize {
+        self.get_point_count_by_age_at(max_age_seconds, Instant::now())
+    }
+
+    /// Points not older than `max_age_seconds` at `now`.
+    pub fn get_point_count_by_age_at(&self, max_age_seconds: f64, now: Instant) -> usize {
         self.map
             .values()
             .flat_map(|points| points.iter())
-            .filter(|point| point.age_seconds() <= max_age_seconds)
+            .filter(|point| point.age_seconds_at(now) <= max_age_seconds)
             .count()
     }
 
     pub fn get_oldest_point_age(&self) -> Option<f64> {
+        self.get_oldest_point_age_at(Instant::now())
+    }
+
+    /// Age of the oldest point at `now`, None for an empty map.
+    pub fn get_oldest_point_age_at(&self, now: Instant) -> Option<f64> {
         self.map
             .values()
             .flat_map(|points| points.iter())
-            .map(|point| point.age_seconds())
-            .max_by(|a, b| a.partial_cmp(b).unwrap())
+            .map(|point| point.age_seconds_at(now))
+            .max_by(|a, b| a.total_cmp(b))
+    }
+
+    /// The `n` points with the latest timestamps across the whole map, newest first.
+    pub fn newest_points(&self, n: usize) -> Vec<P> {
+        if n == 0 {
+            return Vec::new();
+        }
+        let mut points: Vec<P> = self.map.values().flat_map(|v| v.iter()).copied().collect();
+        if n < points.len() {
+            // partial selection, only the n newest get sorted
+            points.select_nth_unstable_by_key(n - 1, |p| Reverse(p.timestamp()));
+            points.truncate(n);
+        }
+        points.sort_unstable_by_key(|p| Reverse(p.timestamp()));
+        points
+    }
+
+    /// Oldest and newest timestamps among the points of `voxel`. A long span hints
+    /// at a stable surface, a short one at a transient observation.
+    pub fn voxel_time_span(&self, voxel: &Voxel) -> Option<(Instant, Instant)> {
+        self.map.get(voxel)?.iter().fold(None, |span, pt| {
+            let t = pt.timestamp();
+            Some(match span {
+                Some((min, max)) => (t.min(min), t.max(max)),
+                None => (t, t),
+            })
+        })
+    }
+
+    /// Up to `k` nearest points to `point` with their distance, closest first. The
+    /// search radius grows from one voxel until the `k` results are certain to be the
+    /// nearest ones or it reaches `max_adjacent_voxels`.
+    pub fn get_k_nearest_neighbors(&self, point: &P, k: usize) -> Vec<(P, f64)> {
+        if k == 0 || self.map.is_empty() {
+            return Vec::new();
+        }
+        let voxel = self.voxel_of_position(&point.position());
+        let point_na = point.position();
+        let mut candidates = Vec::new();
+        for radius in 1..=self.max_adjacent_voxels.max(1) {
+            candidates.clear();
+            candidates.extend(
+                get_adjacent_voxels(&voxel, radius, self.max_adjacent_voxels.max(1))
+                    .filter_map(|query_voxel| self.map.get(&query_voxel))
+                    .flat_map(|voxel_points| voxel_points.iter())
+                    .map(|pt| (*pt, (pt.position() - point_na).norm())),
+            );
+            // anything closer than this lies inside the searched voxels
+            let covered = radius as f64 * self.min_voxel_size();
+            if candidates.iter().filter(|(_, d)| *d <= covered).count() >= k {
+                break;
+            }
+        }
+        candidates.sort_unstable_by(|a, b| a.1.total_cmp(&b.1));
+        candidates.truncate(k);
+        candidates
+    }
+
+    /// Closest map point in the surrounding 3x3x3 voxels, see
+    /// [`VoxelHashMap::get_closest_neighbor_in_radius`].
+    pub fn get_closest_neighbor(&self, point: &P) -> Option<(P, f64)> {
+        self.get_closest_neighbor_in_radius(point, 1)
     }
 
-    pub fn get_closest_neighbor(
+    /// Closest map point within `voxel_radius` voxels of `point` along each axis, for
+    /// voxels small relative to the correspondence distance. The search cost grows
+    /// with the cube of the radius, which is clamped to `max_adjacent_voxels`.
+    pub fn get_closest_neighbor_in_radius(&self, point: &P, voxel_radius: i32) -> Option<(P, f64)> {
+        self.closest_neighbor(point, voxel_radius, f64::INFINITY)
+    }
+
+    /// Like [`VoxelHashMap::get_closest_neighbor`] but `None` when the closest point
+    /// is farther than `max_distance`, farther points are skipped during the search.
+    pub fn get_closest_neighbor_within(&self, point: &P, max_distance: f64) -> Option<(P, f64)> {
+        self.closest_neighbor(point, 1, max_distance)
+    }
+
+    pub(crate) fn closest_neighbor(
+        &self,
+        point: &P,
+        voxel_radius: i32,
+        max_distance: f64,
+    ) -> Option<(P, f64)> {
+        let point_na = point.position();
+        let voxel = self.voxel_of_position(&point_na);
+        let max_distance2 = max_distance * max_distance;
+        get_adjacent_voxels(&voxel, voxel_radius, self.max_adjacent_voxels)
+            .filter_map(|query_voxel| self.map.get(&query_voxel))
+            .flat_map(|voxel_points| voxel_points.iter())
+            .map(|pt| (pt, (pt.position() - point_na).norm_squared()))
+            .filter(|(_, distance2)| *distance2 <= max_distance2)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(pt, distance2)| (*pt, distance2.sqrt()))
+    }
+
+    /// Surface normal at `point` from the map points within a voxel, see
+    /// [`voxel_util::estimate_normal`]. With a sensor `origin` the normal is flipped
+    /// to face it.
+    pub fn estimate_normal(
         &self,
-        point: &point3d::Point3d,
-    ) -> Option<(point3d::Point3d, f64)> {
-        let voxel = voxel_util::point_to_voxel(point, self.voxel_size);
-        let query_voxels = get_adjacent_voxels(&voxel, 1);
-        let point_na = point.to_na_vec_f64();
-        let neighbors: Vec<(point3d::Point3d, f64)> = query_voxels
+        point: &P,
+        origin: Option<&na::Vector3<f64>>,
+    ) -> Option<na::Vector3<f64>> {
+        let position = point.position();
+        let normal = voxel_util::estimate_normal(
+            &self.neighbor_positions(&position, self.min_voxel_size()),
+        )?;
+        match origin {
+            Some(origin) if normal.dot(&(origin - position)) < 0.0 => Some(-normal),
+            _ => Some(normal),
+        }
+    }
+
+    pub(crate) fn neighbor_positions(
+        &self,
+        position: &na::Vector3<f64>,
+        radius: f64,
+    ) -> Vec<na::Vector3<f64>> {
+        self.neighbors(position, radius)
             .iter()
-            .filter_map(|query_voxel| {
-                if let Some(voxel_points) = self.map.get(query_voxel) {
-                    let neighbor = voxel_points
-                        .iter()
-                        .reduce(|acc, pt| {
-                            if (acc.to_na_vec_f64() - point_na).norm()
-                                < (pt.to_na_vec_f64() - point_na).norm()
-                            {
-                                acc
-                            } else {
-                                pt
-                            }
-                        })
-                        .unwrap();
-                    let distance = (neighbor.to_na_vec_f64() - point_na).norm();
-                    Some((*neighbor, distance))
-                } else {
-                    None
+            .map(|pt| pt.position())
+            .collect()
+    }
+
+    pub(crate) fn neighbors(&self, position: &na::Vector3<f64>, radius: f64) -> Vec<P> {
+        self.within_radius(position, radius)
+            .into_iter()
+            .map(|(pt, _)| pt)
+            .collect()
+    }
+
+    /// Map points within `radius` of `point` with their distance, unordered. The
+    /// searched voxels are capped by `max_adjacent_voxels`.
+    pub fn points_within_radius(&self, point: &P, radius: f64) -> Vec<(P, f64)> {
+        self.within_radius(&point.position(), radius)
+    }
+
+    fn within_radius(&self, position: &na::Vector3<f64>, radius: f64) -> Vec<(P, f64)> {
+        let voxel = self.voxel_of_position(position);
+        let adjacent_voxels = (radius / self.min_voxel_size()).ceil().max(1.0) as i32;
+        get_adjacent_voxels(&voxel, adjacent_voxels, self.max_adjacent_voxels)
+            .filter_map(|query_voxel| self.map.get(&query_voxel))
+            .flat_map(|voxel_points| voxel_points.iter())
+            .map(|pt| (*pt, (pt.position() - position).norm()))
+            .filter(|(_, distance)| *distance <= radius)
+            .collect()
+    }
+
+    /// Fraction of `points`, placed at `pose`, with a map point closer than `max_distance`.
+    pub fn estimate_overlap(
+        &self,
+        points: &[P],
+        pose: &na::Isometry3<f64>,
+        max_distance: f64,
+    ) -> f64 {
+        if points.is_empty() {
+            return 0.0;
+        }
+        let overlapping = points
+            .iter()
+            .filter(|pt| {
+                let in_map = pt.with_position(&pose.transform_point(&pt.position().into()).coords);
+                self.get_closest_neighbor(&in_map)
+                    .is_some_and(|(_, distance)| distance < max_distance)
+            })
+            .count();
+        overlapping as f64 / points.len() as f64
+    }
+
+    /// Nearest voxel centroid among the surrounding voxels, a coarse but cheap
+    /// stand-in for the closest point since no voxel's points are scanned.
+    pub fn get_closest_centroid_neighbor(&self, point: &P) -> Option<(na::Vector3<f64>, f64)> {
+        let voxel = self.voxel_of_position(&point.position());
+        let point_na = point.position();
+        get_adjacent_voxels(&voxel, 1, self.max_adjacent_voxels)
+            .filter_map(|query_voxel| self.centroids.get(&query_voxel))
+            .map(|centroid| (*centroid, (centroid - point_na).norm()))
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+
+    /// Closest neighbor in the joint geometric-intensity space, minimizing
+    /// `d^2 + (intensity_weight * delta_intensity)^2`, with its Euclidean distance `d`.
+    pub fn get_closest_neighbor_with_intensity(
+        &self,
+        point: &P,
+        intensity_weight: f64,
+    ) -> Option<(P, f64)> {
+        self.closest_neighbor_with_intensity(point, intensity_weight, 1, f64::INFINITY)
+    }
+
+    /// [`VoxelHashMap::get_closest_neighbor_with_intensity`] within `voxel_radius`
+    /// voxels, skipping points farther than `max_distance` (Euclidean).
+    pub(crate) fn closest_neighbor_with_intensity(
+        &self,
+        point: &P,
+        intensity_weight: f64,
+        voxel_radius: i32,
+        max_distance: f64,
+    ) -> Option<(P, f64)> {
+        let point_na = point.position();
+        let voxel = self.voxel_of_position(&point_na);
+        let max_distance2 = max_distance * max_distance;
+        get_adjacent_voxels(&voxel, voxel_radius, self.max_adjacent_voxels)
+            .filter_map(|query_voxel| self.map.get(&query_voxel))
+            .flat_map(|voxel_points| voxel_points.iter())
+            .map(|pt| (pt, (pt.position() - point_na).norm_squared()))
+            .filter(|(_, distance2)| *distance2 <= max_distance2)
+            .map(|(pt, distance2)| {
+                let di = intensity_weight * (pt.intensity() - point.intensity()) as f64;
+                (pt, distance2, distance2 + di * di)
+            })
+            .min_by(|a, b| a.2.total_cmp(&b.2))
+            .map(|(pt, distance2, _)| (*pt, distance2.sqrt()))
+    }
+}
+
+/// Read-only voxel map, only the `&self` query methods of [`VoxelHashMap`] are
+/// reachable, so nothing can insert or prune points once frozen.
+///
+/// ```compile_fail
+/// use simple_icp::{point3d::Point3d, voxel_hash_map::VoxelHashMap};
+///
+/// let mut frozen = VoxelHashMap::<Point3d>::default_values().freeze();
+/// frozen.clear();
+/// ```
+pub struct FrozenVoxelMap<P = point3d::Point3d> {
+    inner: VoxelHashMap<P>,
+}
+
+impl<P> Deref for FrozenVoxelMap<P> {
+    type Target = VoxelHashMap<P>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<P: PointLike> VoxelHashMap<P> {
+    /// Parallel [`VoxelHashMap::update_with_pose`] for dense scans, the map ends up
+    /// with the same points as the serial insertion.
+    pub fn update_with_pose_par(
+        &mut self,
+        points: &[P],
+        t_origin_current: &na::Isometry3<f64>,
+        sensor_to_body: &na::Isometry3<f64>,
+    ) {
+        self.update_with_pose_par_at(points, t_origin_current, sensor_to_body, Instant::now());
+    }
+
+    /// [`VoxelHashMap::update_with_pose_par`] aging the map against `now`.
+    pub fn update_with_pose_par_at(
+        &mut self,
+        points: &[P],
+        t_origin_current: &na::Isometry3<f64>,
+        sensor_to_body: &na::Isometry3<f64>,
+        now: Instant,
+    ) {
+        use rayon::prelude::*;
+
+        let t_origin_sensor = t_origin_current * sensor_to_body;
+        let transformed_points: VoxelPoints<P> = points
+            .par_iter()
+            .map(|pt| self.to_map_frame(pt, &t_origin_sensor, now))
+            .collect();
+        self.add_points_par(&transformed_points);
+        self.prune(&t_origin_sensor.translation.vector, now);
+    }
+
+    fn to_map_frame(&self, pt: &P, t_origin_sensor: &na::Isometry3<f64>, now: Instant) -> P {
+        let pt = pt.with_position(
+            &t_origin_sensor
+                .transform_point(&pt.position().into())
+                .coords,
+        );
+        // Preserve original timestamp unless aging from insertion
+        if self.age_from_insert {
+            pt.with_timestamp(now)
+        } else {
+            pt
+        }
+    }
+
+    fn add_points_par(&mut self, points: &VoxelPoints<P>) {
+        use rayon::prelude::*;
+
+        let params = self.insert_params();
+        // bucket per voxel, the ordered reduce keeps the input order in each bucket
+        let buckets = points
+            .par_iter()
+            .filter(|pt| params.admits_intensity(*pt))
+            .fold(VoxelMap::<VoxelPoints<P>>::default, |mut buckets, pt| {
+                let voxel = self.voxel_of_position(&pt.position());
+                buckets.entry(voxel).or_default().push(*pt);
+                buckets
+            })
+            .reduce(VoxelMap::default, |mut a, b| {
+                for (voxel, mut points) in b {
+                    a.entry(voxel).or_default().append(&mut points);
                 }
+                a
+            });
+        // take the touched voxels out so each bucket owns its voxel points
+        let buckets: Vec<_> = buckets
+            .into_iter()
+            .map(|(voxel, new_points)| {
+                let voxel_points = self.map.remove(&voxel).unwrap_or_default();
+                (voxel, voxel_points, new_points)
+            })
+            .collect();
+        // voxels are independent, insert each bucket as the serial path would
+        let updated: Vec<_> = buckets
+            .into_par_iter()
+            .map(|(voxel, mut voxel_points, new_points)| {
+                let inserted: Vec<P> = new_points
+                    .into_iter()
+                    .filter(|pt| {
+                        matches!(
+                            Self::insert_into_voxel(&mut voxel_points, pt, &params),
+                            InsertOutcome::Added | InsertOutcome::Replaced
+                        )
+                    })
+                    .collect();
+                (voxel, voxel_points, inserted)
             })
             .collect();
-        neighbors
+
+        self.last_batch_points.clear();
+        for (voxel, voxel_points, inserted) in updated {
+            self.centroids.insert(voxel, centroid_of(&voxel_points));
+            self.voxel_bounds = grow_voxel_bounds(self.voxel_bounds, &voxel);
+            self.map.insert(voxel, voxel_points);
+            if self.track_last_batch {
+                self.last_batch_points.extend(inserted);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "bincode")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct MapSnapshot<P> {
+    voxel_size: [f32; 3],
+    max_distance: f64,
+    max_points_per_voxel: usize,
+    origin: [f64; 3],
+    voxels: Vec<([i32; 3], Vec<P>)>,
+}
+
+#[cfg(feature = "bincode")]
+impl<P: PointLike + serde::Serialize + 'static> VoxelHashMap<P> {
+    fn snapshot(&self) -> MapSnapshot<P> {
+        MapSnapshot {
+            voxel_size: self.voxel_size.into(),
+            max_distance: self.max_distance,
+            max_points_per_voxel: self.max_points_per_voxel,
+            origin: self.origin.into(),
+            voxels: self
+                .map
+                .iter()
+                .map(|(voxel, points)| ([voxel.x, voxel.y, voxel.z], points.clone()))
+                .collect(),
+        }
+    }
+
+    /// Writes the map (parameters, voxels and points) in bincode format.
+    pub fn save(&self, path: &std::path::Path) -> std::io::Result<()> {
+        write_snapshot(&self.snapshot(), path)
+    }
+
+    /// Like [`VoxelHashMap::save`] but writes on a background thread. The map is
+    /// copied before returning, so the file holds the map as it was at the call
+    /// whatever updates happen while the write is in flight.
+    pub fn save_async(
+        &self,
+        path: &std::path::Path,
+    ) -> std::thread::JoinHandle<std::io::Result<()>> {
+        let snapshot = self.snapshot();
+        let path = path.to_path_buf();
+        std::thread::spawn(move || write_snapshot(&snapshot, &path))
+    }
+}
+
+#[cfg(feature = "bincode")]
+impl<P: PointLike + serde::de::DeserializeOwned> VoxelHashMap<P> {
+    /// Reads a map written by [`VoxelHashMap::save`], failing with `InvalidData` if it
+    /// was built with another `voxel_size`. Other settings keep their default values.
+    pub fn load(path: &std::path::Path, voxel_size: na::Vector3<f32>) -> std::io::Result<Self> {
+        let reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        let snapshot: MapSnapshot<P> = bincode::deserialize_from(reader)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if na::Vector3::from(snapshot.voxel_size) != voxel_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "map voxel size {:?} does not match {:?}",
+                    snapshot.voxel_size,
+                    voxel_size.as_slice()
+                ),
+            ));
+        }
+        let map: VoxelMap<VoxelPoints<P>> = snapshot
+            .voxels
+            .into_iter()
+            .filter(|(_, points)| !points.is_empty())
+            .map(|(voxel, points)| (Voxel::from(voxel), points))
+            .collect();
+        Ok(VoxelHashMap {
+            voxel_size: snapshot.voxel_size.into(),
+            max_distance: snapshot.max_distance,
+            max_points_per_voxel: snapshot.max_points_per_voxel,
+            centroids: map
+                .iter()
+                .map(|(voxel, points)| (*voxel, centroid_of(points)))
+                .collect(),
+            voxel_bounds: voxel_bounds_of(map.keys()),
+            map,
+            origin: snapshot.origin.into(),
+            ..VoxelHashMap::default_values()
+        })
+    }
+}
+
+#[cfg(feature = "bincode")]
+fn write_snapshot<P: serde::Serialize>(
+    snapshot: &MapSnapshot<P>,
+    path: &std::path::Path,
+) -> std::io::Result<()> {
+    let writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+    bincode::serialize_into(writer, snapshot).map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point3d::Point3d;
+
+    fn insert(map: &mut VoxelHashMap, points: &[Point3d]) {
+        map.update_with_pose(
+            points,
+            &na::Isometry3::identity(),
+            &na::Isometry3::identity(),
+        );
+    }
+
+    #[test]
+    fn blended_intensity_converges_to_the_latest_observation() {
+        let mut map = VoxelHashMap {
+            intensity_blend: Some(0.5),
+            ..VoxelHashMap::default_values()
+        };
+        insert(&mut map, &[Point3d::new(1.5, 1.5, 1.5, 0.0)]);
+        for _ in 0..20 {
+            insert(&mut map, &[Point3d::new(1.5, 1.5, 1.5, 10.0)]);
+        }
+        let stored = map.iter_points().collect::<Vec<_>>();
+        assert_eq!(stored.len(), 1);
+        assert!((stored[0].intensity - 10.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn intensity_breaks_geometric_ties() {
+        let mut map = VoxelHashMap::default_values();
+        insert(
+            &mut map,
+            &[
+                Point3d::new(2.0, 2.5, 2.5, 10.0),
+                Point3d::new(3.0, 2.5, 2.5, 50.0),
+            ],
+        );
+        // both map points are 0.5 away
+        for intensity in [10.0, 50.0] {
+            let query = Point3d::new(2.5, 2.5, 2.5, intensity);
+            let (closest, _) = map
+                .get_closest_neighbor_with_intensity(&query, 0.1)
+                .unwrap();
+            assert_eq!(closest.intensity, intensity);
+        }
+    }
+
+    #[test]
+    fn points_in_voxel_returns_the_inserted_point() {
+        let mut map = VoxelHashMap::default_values();
+        let point = Point3d::new(3.2, -1.7, 0.4, 5.0);
+        insert(&mut map, &[point]);
+        let voxel = map.voxel_of(&point);
+        assert_eq!(voxel, Voxel::new(3, -2, 0));
+        let points = map.points_in_voxel(&voxel).unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].to_na_vec_f64(), point.to_na_vec_f64());
+        assert!(map.points_in_voxel(&Voxel::new(0, 0, 0)).is_none());
+    }
+
+    #[test]
+    fn frozen_map_answers_neighbor_queries() {
+        let mut map = VoxelHashMap::default_values();
+        insert(&mut map, &[Point3d::new(1.2, 1.2, 1.2, 0.0)]);
+        let frozen = map.freeze();
+        let (closest, distance) = frozen
+            .get_closest_neighbor(&Point3d::new(1.0, 1.2, 1.2, 0.0))
+            .unwrap();
+        assert_eq!(closest.x, 1.2);
+        assert!((distance - 0.2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn voxel_time_span_covers_its_points() {
+        let mut map = VoxelHashMap::default_values();
+        let t0 = Instant::now();
+        let at = |secs: u64| t0 + std::time::Duration::from_secs(secs);
+        insert(
+            &mut map,
+            &[
+                Point3d::new_with_timestamp(0.1, 0.1, 0.1, 0.0, at(1)),
+                Point3d::new_with_timestamp(0.5, 0.5, 0.5, 0.0, at(3)),
+                Point3d::new_with_timestamp(0.9, 0.9, 0.9, 0.0, at(2)),
+            ],
+        );
+        assert_eq!(map.voxel_time_span(&Voxel::zeros()), Some((at(1), at(3))));
+        assert_eq!(map.voxel_time_span(&Voxel::new(1, 0, 0)), None);
+    }
+
+    #[test]
+    fn points_in_frame() {
+        let mut map = VoxelHashMap::default_values();
+        let pose = na::Isometry3::new(na::Vector3::new(4.0, -2.0, 1.0), na::Vector3::z() * 0.7);
+        let scan = [
+            Point3d::new(1.0, 2.0, 0.5, 0.0),
+            Point3d::new(-3.0, 5.0, 1.5, 0.0),
+        ];
+        map.update_with_pose(&scan, &pose, &na::Isometry3::identity());
+        assert_eq!(
+            map.get_na_points_in_frame(&na::Isometry3::identity()),
+            map.get_na_points()
+        );
+        let mut in_sensor = map.get_na_points_in_frame(&pose);
+        in_sensor.sort_by(|a, b| a.x.total_cmp(&b.x));
+        for (p, pt) in in_sensor.iter().zip([scan[1], scan[0]]) {
+            assert!((p - pt.position()).norm() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn aging_is_unaffected_by_a_consistent_rebase() {
+        let mut map = VoxelHashMap {
+            max_point_age_seconds: Some(2.0),
+            ..VoxelHashMap::default_values()
+        };
+        let from_epoch = Instant::now();
+        let at = |epoch: Instant, secs: u64| epoch + std::time::Duration::from_secs(secs);
+        insert(
+            &mut map,
+            &[
+                Point3d::new_with_timestamp(0.5, 0.5, 0.5, 0.0, at(from_epoch, 1)),
+                Point3d::new_with_timestamp(5.5, 0.5, 0.5, 0.0, at(from_epoch, 3)),
+            ],
+        );
+        let before = map.stats(at(from_epoch, 4));
+
+        let to_epoch = at(from_epoch, 100);
+        map.rebase_timestamps(from_epoch, to_epoch);
+        let after = map.stats(at(to_epoch, 4));
+        assert_eq!(after.oldest_age, before.oldest_age);
+        assert_eq!(after.newest_age, before.newest_age);
+
+        map.remove_aged_points_at(at(to_epoch, 4));
+        let kept = map.iter_points().collect::<Vec<_>>();
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].timestamp(), at(to_epoch, 3));
+    }
+
+    #[test]
+    fn newest_points_span_every_insert() {
+        let mut map = VoxelHashMap::default_values();
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + std::time::Duration::from_millis(ms);
+        // interleaved timestamps across batches and voxels
+        for batch in 0..3u64 {
+            let points: Vec<Point3d> = (0..4u64)
+                .map(|i| {
+                    let x = (batch * 4 + i) as f32 * 2.0 + 0.5;
+                    Point3d::new_with_timestamp(x, 0.5, 0.5, 0.0, at((i * 3 + batch) * 10))
+                })
+                .collect();
+            insert(&mut map, &points);
+        }
+        let newest: Vec<Instant> = map.newest_points(3).iter().map(|p| p.timestamp()).collect();
+        assert_eq!(newest, vec![at(110), at(100), at(90)]);
+        assert_eq!(map.newest_points(100).len(), 12);
+        assert!(map.newest_points(0).is_empty());
+    }
+
+    #[test]
+    fn delayed_point_ages_from_insert() {
+        let second = std::time::Duration::from_secs(1);
+        for (age_from_insert, kept) in [(true, 1), (false, 0)] {
+            let mut map = VoxelHashMap {
+                max_point_age_seconds: Some(2.0),
+                age_from_insert,
+                ..VoxelHashMap::default_values()
+            };
+            // captured a second before the insert
+            let capture = Instant::now().checked_sub(second).unwrap();
+            insert(
+                &mut map,
+                &[Point3d::new_with_timestamp(0.5, 0.5, 0.5, 0.0, capture)],
+            );
+            map.remove_aged_points_at(Instant::now() + second.mul_f64(1.5));
+            assert_eq!(map.iter_points().count(), kept);
+        }
+    }
+
+    #[test]
+    fn occupancy_inside_and_outside_the_map() {
+        let mut map = VoxelHashMap::default_values();
+        insert(
+            &mut map,
+            &[
+                Point3d::new(0.5, 0.5, 0.5, 0.0),
+                Point3d::new(5.5, 5.5, 5.5, 0.0),
+            ],
+        );
+        let inserted = na::Vector3::new(0.5, 0.5, 0.5);
+        let between = na::Vector3::new(2.5, 2.5, 2.5);
+        let far = na::Vector3::new(50.0, 0.5, 0.5);
+        assert!(map.is_occupied(&inserted) && !map.is_free(&inserted));
+        assert!(!map.is_occupied(&between) && map.is_free(&between));
+        // unknown: neither occupied nor free
+        assert!(!map.is_occupied(&far) && !map.is_free(&far));
+
+        // the bounds shrink with the map
+        map.remove_points_in_aabb(&na::Vector3::repeat(5.0), &na::Vector3::repeat(6.0));
+        assert!(!map.is_free(&between));
+        map.clear();
+        assert!(!map.is_free(&inserted));
+    }
+
+    #[test]
+    fn closest_centroid_is_near_the_closest_point() {
+        let mut map = VoxelHashMap::default_values();
+        // 16 points per voxel on the z = 0.5 plane
+        let plane: Vec<Point3d> = (0..400)
+            .map(|i| Point3d::new(0.25 * (i % 20) as f32, 0.25 * (i / 20) as f32, 0.5, 0.0))
+            .collect();
+        insert(&mut map, &plane);
+        for query in [
+            Point3d::new(2.3, 3.6, 1.4, 0.0),
+            Point3d::new(0.1, 4.9, 0.2, 0.0),
+            Point3d::new(3.9, 0.4, 0.9, 0.0),
+        ] {
+            let (closest, _) = map.get_closest_neighbor(&query).unwrap();
+            let (centroid, distance) = map.get_closest_centroid_neighbor(&query).unwrap();
+            assert!((centroid - closest.position()).norm() < map.min_voxel_size());
+            assert!((distance - (centroid - query.position()).norm()).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn huge_neighborhood_radius_is_clamped() {
+        let voxel = Voxel::new(3, -1, 7);
+        assert_eq!(get_adjacent_voxels(&voxel, i32::MAX, 2).count(), 125);
+        assert!(get_adjacent_voxels(&voxel, 5, -4).eq([voxel]));
+        assert!(get_adjacent_voxels(&voxel, -5, 4).eq([voxel]));
+    }
+
+    #[test]
+    fn stats_match_the_getters() {
+        let mut map = VoxelHashMap::default_values();
+        let t0 = Instant::now();
+        let now = t0 + std::time::Duration::from_secs(5);
+        let ago = |secs: u64| now - std::time::Duration::from_secs(secs);
+        map.update_with_pose_at(
+            &[
+                Point3d::new_with_timestamp(0.5, 0.5, 0.5, 0.0, ago(1)),
+                Point3d::new_with_timestamp(2.2, 0.5, 0.5, 0.0, ago(2)),
+                Point3d::new_with_timestamp(2.8, 0.5, 0.5, 0.0, ago(3)),
+                Point3d::new_with_timestamp(4.5, -3.0, 7.0, 0.0, ago(5)),
+            ],
+            &na::Isometry3::identity(),
+            &na::Isometry3::identity(),
+            now,
+        );
+        let stats = map.stats(now);
+        assert_eq!(stats.num_voxels, map.iter_voxels().count());
+        assert_eq!(stats.num_points, map.map_len());
+        assert_eq!(stats.max_points_per_voxel, 2);
+        assert!((stats.avg_points_per_voxel - 4.0 / 3.0).abs() < 1e-12);
+        assert_eq!(stats.bounds, map.aabb());
+        assert_eq!(stats.oldest_age, map.get_oldest_point_age_at(now));
+        assert_eq!(map.health_at(2.0, now).oldest_age, stats.oldest_age);
+        assert_eq!(map.health_at(2.0, now).num_recent_points, 2);
+        assert_eq!(map.get_point_count_by_age_at(2.0, now), 2);
+        assert!((stats.oldest_age.unwrap() - 5.0).abs() < 1e-9);
+        assert!((stats.newest_age.unwrap() - 1.0).abs() < 1e-9);
+        assert_eq!(stats.memory_bytes, map.memory_usage().memory_bytes);
+    }
+
+    #[cfg(feature = "bincode")]
+    #[test]
+    fn async_save_holds_the_map_at_the_call() {
+        let mut map = VoxelHashMap::default_values();
+        insert(&mut map, &[Point3d::new(0.5, 0.5, 0.5, 1.0)]);
+        let saved = map.get_na_points();
+        let path = std::env::temp_dir().join(format!("simple_icp_{}_map.bin", std::process::id()));
+        let handle = map.save_async(&path);
+        // the loop goes on while the write is in flight
+        for i in 1..50 {
+            insert(
+                &mut map,
+                &[Point3d::new(2.0 * i as f32 + 0.5, 0.5, 0.5, 1.0)],
+            );
+        }
+        handle.join().unwrap().unwrap();
+        assert_eq!(map.map_len(), 50);
+
+        let loaded = VoxelHashMap::<Point3d>::load(&path, map.voxel_size).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.get_na_points(), saved);
+    }
+
+    #[test]
+    fn extrinsic_equals_pre_transforming() {
+        let scan: Vec<Point3d> = (0..30)
+            .map(|i| Point3d::new((i % 6) as f32 * 1.5, (i / 6) as f32 * 1.5, 2.0, 0.0))
+            .collect();
+        let pose = na::Isometry3::new(na::Vector3::new(3.0, -1.0, 0.5), na::Vector3::z() * 0.4);
+        let sensor_to_body =
+            na::Isometry3::new(na::Vector3::new(0.2, 0.0, 1.1), na::Vector3::y() * 0.1);
+        let mut with_extrinsic = VoxelHashMap::default_values();
+        with_extrinsic.update_with_pose(&scan, &pose, &sensor_to_body);
+        let mut pre_transformed = VoxelHashMap::default_values();
+        pre_transformed.update_with_pose(
+            &point3d::transform_points(&scan, &sensor_to_body),
+            &pose,
+            &na::Isometry3::identity(),
+        );
+
+        let expected = pre_transformed.get_na_points();
+        let points = with_extrinsic.get_na_points();
+        assert_eq!(points.len(), expected.len());
+        for p in &points {
+            assert!(expected.iter().any(|e| (p - e).norm() < 1e-5));
+        }
+    }
+
+    #[test]
+    fn recentering_keeps_coordinates_bounded() {
+        let mut map = VoxelHashMap::default_values();
+        let scan: Vec<Point3d> = (0..20)
+            .map(|i| Point3d::new(i as f32 - 10.0, 3.0, 0.5, 0.0))
+            .collect();
+        // 10km along x, sensor poses are relative to the map origin
+        let mut sensor = na::Vector3::zeros();
+        for _ in 0..1000 {
+            sensor.x += 10.0;
+            if sensor.norm() > 50.0 {
+                sensor -= map.recenter(&sensor);
+            }
+            map.update_with_pose(
+                &scan,
+                &na::Isometry3::translation(sensor.x, sensor.y, sensor.z),
+                &na::Isometry3::identity(),
+            );
+        }
+        assert!((map.origin.x + sensor.x - 10_000.0).abs() < 1e-9);
+        assert!(map
+            .get_na_points()
             .iter()
-            .reduce(
-                |acc, neighbor| {
-                    if acc.1 < neighbor.1 {
-                        acc
-                    } else {
-                        neighbor
-                    }
-                },
-            )
-            .copied()
+            .all(|p| p.norm() < 50.0 + map.max_distance));
+        // a global query goes through the origin
+        let global = na::Vector3::new(10_000.0 - 2.0, 3.0, 0.5);
+        let query = Point3d::new(0.0, 0.0, 0.0, 0.0).with_position(&(global - map.origin));
+        let (closest, distance) = map.get_closest_neighbor(&query).unwrap();
+        assert!(distance < 1e-5);
+        assert!((closest.position() + map.origin - global).norm() < 1e-5);
+    }
+
+    #[test]
+    fn merge_revoxelizes_a_map_of_another_voxel_size() {
+        let mut coarse = VoxelHashMap {
+            max_points_per_voxel: 2,
+            ..VoxelHashMap::with_voxel_size(2.0)
+        };
+        insert(&mut coarse, &[Point3d::new(0.2, 0.2, 0.2, 0.0)]);
+        let mut fine = VoxelHashMap::with_voxel_size(0.5);
+        // four points in four fine voxels, all in the first coarse voxel, and one in
+        // another coarse voxel
+        insert(
+            &mut fine,
+            &[
+                Point3d::new(0.25, 1.75, 0.25, 0.0),
+                Point3d::new(1.75, 0.25, 0.25, 0.0),
+                Point3d::new(1.75, 1.75, 0.25, 0.0),
+                Point3d::new(0.25, 0.25, 1.75, 0.0),
+                Point3d::new(3.25, 0.25, 0.25, 0.0),
+            ],
+        );
+        assert_eq!(fine.iter_voxels().count(), 5);
+
+        // the first coarse voxel only has room for one more point
+        assert_eq!(coarse.merge(&fine), 2);
+        assert_eq!(coarse.iter_voxels().count(), 2);
+        assert_eq!(coarse.points_in_voxel(&Voxel::zeros()).unwrap().len(), 2);
+        assert_eq!(
+            coarse.points_in_voxel(&Voxel::new(1, 0, 0)).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn empty_map_has_no_points() {
+        let map = VoxelHashMap::<Point3d>::default_values();
+        assert!(map.get_na_points().is_empty());
+    }
+
+    #[test]
+    fn voxels_can_be_flat() {
+        let mut map = VoxelHashMap {
+            voxel_size: na::Vector3::new(2.0, 2.0, 0.25),
+            ..VoxelHashMap::default_values()
+        };
+        assert_eq!(map.min_voxel_size(), 0.25);
+        let point = Point3d::new(3.9, -0.1, 0.6, 0.0);
+        assert_eq!(map.voxel_of(&point), Voxel::new(1, -1, 2));
+        insert(
+            &mut map,
+            &[
+                Point3d::new(0.5, 0.5, 0.1, 0.0),
+                Point3d::new(0.5, 0.5, 0.4, 0.0),
+                Point3d::new(1.5, 1.5, 0.1, 0.0),
+            ],
+        );
+        assert_eq!(map.points_in_voxel(&Voxel::new(0, 0, 0)).unwrap().len(), 2);
+        assert_eq!(map.points_in_voxel(&Voxel::new(0, 0, 1)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn clear_keeps_the_config() {
+        let mut map = VoxelHashMap {
+            max_distance: 42.0,
+            max_points_per_voxel: 3,
+            ..VoxelHashMap::with_voxel_size(0.5)
+        };
+        insert(
+            &mut map,
+            &[
+                Point3d::new(1.0, 2.0, 3.0, 0.0),
+                Point3d::new(-4.0, 0.5, 1.0, 0.0),
+            ],
+        );
+        assert!(!map.is_empty());
+        map.clear();
+        assert!(map.is_empty());
+        assert_eq!(map.map_len(), 0);
+        assert!(map.last_batch().is_empty());
+        assert_eq!(map.voxel_bounds, None);
+        assert_eq!(map.voxel_size, na::Vector3::repeat(0.5));
+        assert_eq!(map.max_distance, 42.0);
+        assert_eq!(map.max_points_per_voxel, 3);
+        // still usable after clearing
+        insert(&mut map, &[Point3d::new(1.0, 2.0, 3.0, 0.0)]);
+        assert_eq!(map.map_len(), 1);
+    }
+
+    #[test]
+    fn neighbor_beyond_the_cutoff_is_none() {
+        let mut map = VoxelHashMap::default_values();
+        insert(
+            &mut map,
+            &[
+                Point3d::new(1.9, 0.5, 0.5, 0.0),
+                Point3d::new(0.5, 1.9, 0.5, 0.0),
+            ],
+        );
+        let query = Point3d::new(0.5, 0.5, 0.5, 0.0);
+        // both points are in the searched voxels, 1.4 away
+        assert!(map.get_closest_neighbor(&query).is_some());
+        assert!(map.get_closest_neighbor_within(&query, 1.0).is_none());
+        let (_, distance) = map.get_closest_neighbor_within(&query, 1.5).unwrap();
+        assert!((distance - 1.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn voxel_straddling_the_range_keeps_its_near_points() {
+        let mut map = VoxelHashMap::with_voxel_size(8.0);
+        // same voxel, the first point 100.1 m away and the second 98.5 m away
+        let far = Point3d::new(98.0, 20.5, 0.0, 0.0);
+        let near = Point3d::new(97.0, 17.0, 0.0, 0.0);
+        assert_eq!(map.voxel_of(&far), map.voxel_of(&near));
+        insert(&mut map, &[far, near]);
+        let points = map.points_in_voxel(&map.voxel_of(&near)).unwrap();
+        assert_eq!(points.len(), 1);
+        assert_eq!(points[0].to_na_vec_f64(), near.to_na_vec_f64());
+    }
+
+    #[test]
+    fn range_is_measured_from_the_sensor() {
+        let mut map = VoxelHashMap::default_values();
+        assert_eq!(map.max_distance, 100.0);
+        // 149 m and 51 m from the map origin, 99 m and 101 m from the sensor
+        let sensor = na::Isometry3::translation(50.0, 0.0, 0.0);
+        map.update_with_pose(
+            &[
+                Point3d::new(99.0, 0.5, 0.5, 0.0),
+                Point3d::new(-101.0, 0.5, 0.5, 0.0),
+            ],
+            &sensor,
+            &na::Isometry3::identity(),
+        );
+        let kept = map.get_na_points();
+        assert_eq!(kept.len(), 1);
+        assert!((kept[0].x - 149.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn neighbor_search_next_to_aged_out_voxels() {
+        let t0 = Instant::now();
+        let at = |secs: u64| t0 + std::time::Duration::from_secs(secs);
+        let mut map = VoxelHashMap {
+            max_point_age_seconds: Some(10.0),
+            ..VoxelHashMap::default_values()
+        };
+        insert(
+            &mut map,
+            &[
+                Point3d::new_with_timestamp(0.5, 0.5, 0.5, 0.0, at(0)),
+                Point3d::new_with_timestamp(1.5, 0.5, 0.5, 0.0, at(5)),
+            ],
+        );
+        let query = Point3d::new(0.4, 0.5, 0.5, 0.0);
+        assert!((map.get_closest_neighbor(&query).unwrap().1 - 0.1).abs() < 1e-6);
+
+        // the query voxel ages out, its neighbor answers
+        map.remove_aged_points_at(at(12));
+        assert!(map.points_in_voxel(&Voxel::zeros()).is_none());
+        let (closest, _) = map.get_closest_neighbor(&query).unwrap();
+        assert_eq!(closest.x, 1.5);
+        assert_eq!(map.get_k_nearest_neighbors(&query, 2).len(), 1);
+
+        map.remove_aged_points_at(at(20));
+        assert!(map.is_empty());
+        assert!(map.get_closest_neighbor(&query).is_none());
+        assert!(map.get_k_nearest_neighbors(&query, 2).is_empty());
+        assert!(map.get_closest_centroid_neighbor(&query).is_none());
+
+        // a stale empty voxel is skipped rather than crashing the search
+        map.map.insert(Voxel::zeros(), Vec::new());
+        assert!(map.get_closest_neighbor(&query).is_none());
+        assert!(map
+            .get_closest_neighbor_with_intensity(&query, 0.1)
+            .is_none());
+        assert!(map.get_k_nearest_neighbors(&query, 2).is_empty());
+    }
+
+    #[test]
+    fn points_exactly_at_the_age_limit_are_kept() {
+        let t0 = Instant::now();
+        let at = |millis: u64| t0 + std::time::Duration::from_millis(millis);
+        let mut map = VoxelHashMap {
+            max_point_age_seconds: Some(10.0),
+            ..VoxelHashMap::default_values()
+        };
+        let oldest = Point3d::new_with_timestamp(0.5, 0.5, 0.5, 0.0, at(0));
+        let at_limit = Point3d::new_with_timestamp(2.5, 0.5, 0.5, 0.0, at(1));
+        // inserting at 10.001 s ages the first point past the limit, the second exactly to it
+        map.update_with_pose_at(
+            &[oldest, at_limit],
+            &na::Isometry3::identity(),
+            &na::Isometry3::identity(),
+            at(10_001),
+        );
+        let kept = map.get_na_points();
+        assert_eq!(kept, vec![at_limit.to_na_vec_f64()]);
+        assert_eq!(map.get_oldest_point_age_at(at(10_001)), Some(10.0));
+
+        map.remove_aged_points_at(at(10_002));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn mirror_applying_deltas_matches_the_map() {
+        let t0 = Instant::now();
+        let at = |secs: u64| t0 + std::time::Duration::from_secs(secs);
+        let pt = |x: f32, y: f32, z: f32, intensity: f32, secs: u64| {
+            Point3d::new_with_timestamp(x, y, z, intensity, at(secs))
+        };
+        let updates = [
+            (
+                0,
+                0.0,
+                vec![
+                    pt(0.1, 0.1, 0.1, 1.0, 0),
+                    pt(0.9, 0.9, 0.9, 1.0, 0),
+                    pt(4.5, 0.5, 0.5, 1.0, 0),
+                    pt(2.5, 0.5, 0.5, 1.0, 0),
+                ],
+            ),
+            // a third point in the full first voxel and a duplicate in the third one
+            (
+                5,
+                0.0,
+                vec![
+                    pt(0.5, 0.1, 0.9, 3.0, 5),
+                    pt(2.6, 0.5, 0.5, 5.0, 5),
+                    pt(1.5, 0.5, 0.5, 1.0, 5),
+                    pt(3.2, 0.5, 0.5, 1.0, 5),
+                    pt(3.9, 0.9, 0.9, 1.0, 5),
+                ],
+            ),
+            // the first points age out and the sensor moves away, splitting the voxel
+            // at x = 3 across the range limit
+            (12, -5.0, vec![pt(-3.0, 0.5, 0.5, 1.0, 12)]),
+        ];
+        let same_points = |a: &VoxelHashMap, b: &VoxelHashMap| {
+            let key = |pt: &Point3d| (pt.position(), pt.intensity(), pt.timestamp());
+            assert_eq!(a.map.len(), b.map.len());
+            for (voxel, points) in a.iter_voxels() {
+                let mirrored = b.points_in_voxel(voxel).unwrap();
+                assert!(points.iter().map(key).eq(mirrored.iter().map(key)));
+                assert!((a.centroids[voxel] - b.centroids[voxel]).norm() < 1e-9);
+            }
+            assert_eq!(a.voxel_bounds, b.voxel_bounds);
+        };
+        for (merge_policy, eviction, intensity_blend) in [
+            (
+                MergePolicy::WeightedAverage,
+                EvictionPolicy::EvictOldest,
+                None,
+            ),
+            (MergePolicy::Reject, EvictionPolicy::RejectNew, Some(0.5)),
+        ] {
+            let mut map = VoxelHashMap {
+                max_distance: 8.5,
+                max_points_per_voxel: 2,
+                max_point_age_seconds: Some(10.0),
+                merge_policy,
+                eviction,
+                intensity_blend,
+                ..VoxelHashMap::default_values()
+            };
+            let mut mirror = VoxelHashMap::default_values();
+            for (secs, sensor_x, points) in &updates {
+                let sensor = na::Isometry3::translation(*sensor_x, 0.0, 0.0);
+                let delta = map.update_with_pose_delta_at(
+                    points,
+                    &sensor,
+                    &na::Isometry3::identity(),
+                    at(*secs),
+                );
+                assert_eq!(delta.sensor_origin, sensor.translation.vector);
+                if *secs > 0 {
+                    assert!(!delta.updated.is_empty());
+                }
+                mirror.apply_delta(&delta);
+                same_points(&map, &mirror);
+            }
+        }
+    }
+
+    #[test]
+    fn intensity_selects_but_does_not_stretch_the_distance() {
+        let mut map = VoxelHashMap::default_values();
+        insert(
+            &mut map,
+            &[
+                Point3d::new(0.5, 0.5, 0.5, 100.0),
+                Point3d::new(1.5, 0.5, 0.5, 10.0),
+                Point3d::new(3.5, 0.5, 0.5, 10.0),
+            ],
+        );
+        let query = Point3d::new(0.6, 0.5, 0.5, 10.0);
+        let (closest, distance) = map
+            .get_closest_neighbor_with_intensity(&query, 0.1)
+            .unwrap();
+        assert_eq!((closest.x, closest.intensity), (1.5, 10.0));
+        assert!((distance - 0.9).abs() < 1e-6);
+
+        // same neighborhood and gating as the geometric search
+        let query = Point3d::new(5.5, 0.5, 0.5, 10.0);
+        assert!(map
+            .get_closest_neighbor_with_intensity(&query, 0.1)
+            .is_none());
+        let (closest, distance) = map
+            .closest_neighbor_with_intensity(&query, 0.1, 2, f64::INFINITY)
+            .unwrap();
+        assert_eq!(closest.x, 3.5);
+        assert!((distance - 2.0).abs() < 1e-6);
+        assert!(map
+            .closest_neighbor_with_intensity(&query, 0.1, 2, 1.5)
+            .is_none());
+    }
+
+    #[test]
+    fn dense_keys_spread_over_the_low_hash_bits() {
+        use std::hash::BuildHasher;
+        let keys: Vec<Voxel> = (0..32 * 32 * 8)
+            .map(|i| Voxel::new(i % 32 - 16, i / 32 % 32 - 16, i / 1024))
+            .collect();
+        // hashbrown indexes its buckets with the low bits
+        let num_buckets = (keys.len() * 8 / 7).next_power_of_two();
+        let mut occupied = vec![false; num_buckets];
+        for key in &keys {
+            occupied[VoxelBuildHasher::default().hash_one(key) as usize & (num_buckets - 1)] = true;
+        }
+        // uniform hashing fills about 79% at this load
+        let filled = occupied.iter().filter(|o| **o).count() as f64 / keys.len() as f64;
+        assert!(filled > 0.7, "{filled}");
     }
 }
@@ -1,30 +1,295 @@
 use crate::{
-    point3d::{self, Point3d},
-    voxel_hash_map::{self, Voxel},
+    point3d::PointLike,
+    voxel_hash_map::{self, Voxel, VoxelHashMap, VoxelMap},
 };
 use nalgebra as na;
-use std::collections::HashMap;
 
-pub fn voxel_downsample(point_cloud: &[point3d::Point3d], voxel_size: f32) -> Vec<Point3d> {
-    let grid: HashMap<Voxel, point3d::Point3d> = point_cloud
+pub fn voxel_downsample<P: PointLike>(point_cloud: &[P], voxel_size: f32) -> Vec<P> {
+    let grid: VoxelMap<P> = point_cloud
         .iter()
         .map(|pt| (point_to_voxel(pt, voxel_size), *pt))
         .collect();
     grid.values().cloned().collect()
 }
 
-pub fn point_to_voxel(point: &point3d::Point3d, voxel_size: f32) -> voxel_hash_map::Voxel {
-    Voxel::new(
-        (point.x / voxel_size).floor() as i32,
-        (point.y / voxel_size).floor() as i32,
-        (point.z / voxel_size).floor() as i32,
-    )
+/// [`voxel_downsample`] at a coarse and a fine resolution in one pass, e.g. the
+/// registration source and the map update of KISS-ICP (`voxel_size` and half of
+/// it). When `coarse_size` is a multiple of `fine_size` the coarse voxels are derived
+/// from the fine ones instead of hashing the points twice. Returns (coarse, fine).
+pub fn voxel_downsample_double<P: PointLike>(
+    point_cloud: &[P],
+    coarse_size: f32,
+    fine_size: f32,
+) -> (Vec<P>, Vec<P>) {
+    let ratio = (coarse_size / fine_size).round();
+    if ratio < 1.0 || (coarse_size / fine_size - ratio).abs() > 1e-4 {
+        return (
+            voxel_downsample(point_cloud, coarse_size),
+            voxel_downsample(point_cloud, fine_size),
+        );
+    }
+    let ratio = ratio as i32;
+    let mut fine: VoxelMap<P> = VoxelMap::default();
+    let mut coarse: VoxelMap<P> = VoxelMap::default();
+    for pt in point_cloud {
+        let voxel = point_to_voxel(pt, fine_size);
+        coarse.insert(voxel.map(|v| v.div_euclid(ratio)), *pt);
+        fine.insert(voxel, *pt);
+    }
+    (coarse.into_values().collect(), fine.into_values().collect())
+}
+
+/// One point per occupied voxel at the mean position with the mean intensity and the
+/// newest timestamp (other fields from the newest point), smoother than
+/// [`voxel_downsample`] which keeps an arbitrary point of each voxel.
+pub fn downsample<P: PointLike>(point_cloud: &[P], voxel_size: f32) -> Vec<P> {
+    // (position sum, intensity sum, count, newest point)
+    let mut grid: VoxelMap<(na::Vector3<f64>, f64, usize, P)> = VoxelMap::default();
+    for pt in point_cloud {
+        grid.entry(point_to_voxel(pt, voxel_size))
+            .and_modify(|(position, intensity, count, newest)| {
+                *position += pt.position();
+                *intensity += pt.intensity() as f64;
+                *count += 1;
+                if pt.timestamp() > newest.timestamp() {
+                    *newest = *pt;
+                }
+            })
+            .or_insert((pt.position(), pt.intensity() as f64, 1, *pt));
+    }
+    grid.into_values()
+        .map(|(position, intensity, count, newest)| {
+            newest
+                .with_position(&(position / count as f64))
+                .with_intensity((intensity / count as f64) as f32)
+        })
+        .collect()
 }
 
+/// Downsamples to roughly `target` points by binary searching the voxel size, stopping
+/// once the count is within `tolerance` (a fraction of `target`). A `target` of 0
+/// gives no points.
+pub fn voxel_downsample_to_count<P: PointLike>(
+    point_cloud: &[P],
+    target: usize,
+    tolerance: f64,
+) -> Vec<P> {
+    if target == 0 {
+        return Vec::new();
+    }
+    if point_cloud.len() <= target {
+        return point_cloud.to_vec();
+    }
+    let (min, max) = point_cloud.iter().fold(
+        (na::Vector3::repeat(f64::MAX), na::Vector3::repeat(f64::MIN)),
+        |(min, max), pt| (min.inf(&pt.position()), max.sup(&pt.position())),
+    );
+    // voxels as large as the cloud keep at most 8 points, the grid being aligned on
+    // the origin the cloud straddles at most 2 voxels per axis
+    let mut hi = ((max - min).norm() as f32).max(f32::EPSILON);
+    let mut lo = 0.0f32;
+    let max_error = tolerance * target as f64;
+    let mut best = point_cloud.to_vec();
+    for _ in 0..DOWNSAMPLE_MAX_ITERATIONS {
+        let voxel_size = 0.5 * (lo + hi);
+        let downsampled = voxel_downsample(point_cloud, voxel_size);
+        let count = downsampled.len();
+        if count.abs_diff(target) < best.len().abs_diff(target) {
+            best = downsampled;
+        }
+        if (count.abs_diff(target) as f64) <= max_error {
+            break;
+        }
+        if count > target {
+            lo = voxel_size;
+        } else {
+            hi = voxel_size;
+        }
+    }
+    best
+}
+
+const DOWNSAMPLE_MAX_ITERATIONS: usize = 32;
+
+pub fn point_to_voxel<P: PointLike>(point: &P, voxel_size: f32) -> voxel_hash_map::Voxel {
+    na_vec_to_voxel(&point.position(), voxel_size as f64)
+}
 pub fn na_vec_to_voxel(point: &na::Vector3<f64>, voxel_size: f64) -> voxel_hash_map::Voxel {
+    na_vec_to_voxel_anisotropic(point, &na::Vector3::repeat(voxel_size))
+}
+/// Voxel of `point` in a grid whose cells measure `voxel_size` along each axis.
+pub fn na_vec_to_voxel_anisotropic(
+    point: &na::Vector3<f64>,
+    voxel_size: &na::Vector3<f64>,
+) -> voxel_hash_map::Voxel {
     Voxel::new(
-        (point.x / voxel_size).floor() as i32,
-        (point.y / voxel_size).floor() as i32,
-        (point.z / voxel_size).floor() as i32,
+        (point.x / voxel_size.x).floor() as i32,
+        (point.y / voxel_size.y).floor() as i32,
+        (point.z / voxel_size.z).floor() as i32,
     )
 }
+
+/// Surface normal of a neighborhood: eigenvector of the smallest eigenvalue of the
+/// positions covariance. `None` with fewer than 3 points or when the smallest
+/// eigenvalue is not distinct (points on a line or without any structure).
+pub fn estimate_normal(neighbors: &[na::Vector3<f64>]) -> Option<na::Vector3<f64>> {
+    estimate_surface(neighbors).map(|surface| surface.normal)
+}
+
+/// Local surface fitted by [`estimate_surface`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SurfaceEstimate {
+    pub mean: na::Vector3<f64>,
+    /// Unit normal, sign is arbitrary
+    pub normal: na::Vector3<f64>,
+    /// Smallest covariance eigenvalue over the eigenvalue sum, 0 on a plane
+    pub variation: f64,
+}
+
+/// [`estimate_normal`] along with the mean and surface variation of the neighborhood.
+pub fn estimate_surface(neighbors: &[na::Vector3<f64>]) -> Option<SurfaceEstimate> {
+    if neighbors.len() < 3 {
+        return None;
+    }
+    let n = neighbors.len() as f64;
+    let mean = neighbors.iter().sum::<na::Vector3<f64>>() / n;
+    let covariance = neighbors
+        .iter()
+        .fold(na::Matrix3::<f64>::zeros(), |acc, p| {
+            acc + (p - mean) * (p - mean).transpose()
+        })
+        / n;
+    let eigen = covariance.symmetric_eigen();
+    let mut order = [0, 1, 2];
+    order.sort_unstable_by(|&a, &b| eigen.eigenvalues[a].total_cmp(&eigen.eigenvalues[b]));
+    let [smallest, middle, largest] = order.map(|i| eigen.eigenvalues[i]);
+    if middle - smallest <= NORMAL_MIN_EIGENVALUE_GAP * largest {
+        return None;
+    }
+    Some(SurfaceEstimate {
+        mean,
+        normal: eigen.eigenvectors.column(order[0]).normalize(),
+        variation: smallest / (smallest + middle + largest),
+    })
+}
+
+/// Gap between the two smallest covariance eigenvalues, relative to the largest,
+/// below which the normal is ambiguous.
+const NORMAL_MIN_EIGENVALUE_GAP: f64 = 1e-3;
+
+/// Thins `points` (in the map frame) so their map normals cover the direction space
+/// evenly: normals are binned by azimuth and elevation (`bins` steps each) and every
+/// bin keeps at most its share of the points, so flat surfaces get thinned while
+/// edges and corners survive. Points without a normal are kept.
+pub fn normal_space_sampling<P: PointLike>(
+    points: &[P],
+    map: &VoxelHashMap<P>,
+    bins: usize,
+) -> Vec<P> {
+    let bins = bins.max(1);
+    let mut kept = Vec::new();
+    let mut binned: Vec<Vec<P>> = vec![Vec::new(); bins * bins];
+    for pt in points {
+        let neighbors = map.neighbor_positions(&pt.position(), map.min_voxel_size());
+        let Some(normal) = estimate_normal(&neighbors) else {
+            kept.push(*pt);
+            continue;
+        };
+        // normals are sign-ambiguous, fold them onto the upper hemisphere
+        let normal = if normal.z < 0.0 { -normal } else { normal };
+        let azimuth = (normal.y.atan2(normal.x) + std::f64::consts::PI) / std::f64::consts::TAU;
+        let elevation = normal.z.clamp(0.0, 1.0).asin() / std::f64::consts::FRAC_PI_2;
+        let a = ((azimuth * bins as f64) as usize).min(bins - 1);
+        let e = ((elevation * bins as f64) as usize).min(bins - 1);
+        binned[e * bins + a].push(*pt);
+    }
+    let quota = points.len().div_ceil(bins * bins).max(1);
+    for bin in binned {
+        // even stride through the bin so the kept points stay spread out
+        let step = bin.len().div_ceil(quota).max(1);
+        kept.extend(bin.into_iter().step_by(step));
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::point3d::Point3d;
+
+    fn grid(n: usize, spacing: f32) -> Vec<Point3d> {
+        (0..n * n * n)
+            .map(|i| {
+                let [x, y, z] = [i % n, i / n % n, i / (n * n)].map(|c| c as f32 * spacing);
+                Point3d::new(x, y, z, 0.0)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn downsampled_count_is_within_tolerance() {
+        for (cloud, target) in [
+            (grid(12, 0.1), 200),
+            (grid(20, 0.3), 200),
+            (grid(30, 0.05), 1000),
+        ] {
+            let count = voxel_downsample_to_count(&cloud, target, 0.1).len();
+            assert!(
+                count.abs_diff(target) as f64 <= 0.1 * target as f64,
+                "{count} for {target}"
+            );
+        }
+        assert!(voxel_downsample_to_count(&grid(5, 1.0), 0, 0.1).is_empty());
+    }
+
+    #[test]
+    fn normal_space_sampling_keeps_the_edges() {
+        let floor: Vec<Point3d> = (0..1600)
+            .map(|i| Point3d::new(0.5 * (i % 40) as f32, 0.5 * (i / 40) as f32, 0.0, 0.0))
+            .collect();
+        let wall: Vec<Point3d> = (0..80)
+            .map(|i| Point3d::new(5.0, 0.5 * (i % 20) as f32, 0.5 * (1 + i / 20) as f32, 0.0))
+            .collect();
+        let scene: Vec<Point3d> = floor.iter().chain(&wall).copied().collect();
+        let mut map = VoxelHashMap::default_values();
+        map.update_with_pose(
+            &scene,
+            &na::Isometry3::identity(),
+            &na::Isometry3::identity(),
+        );
+
+        let sampled = normal_space_sampling(&scene, &map, 4);
+        let kept_ratio = |points: &[Point3d]| {
+            let kept = sampled
+                .iter()
+                .filter(|s| points.iter().any(|pt| pt.position() == s.position()))
+                .count();
+            kept as f64 / points.len() as f64
+        };
+        assert!(kept_ratio(&wall) > 0.9, "{}", kept_ratio(&wall));
+        assert!(kept_ratio(&floor) < 0.2, "{}", kept_ratio(&floor));
+    }
+
+    #[test]
+    fn downsample_keeps_the_centroid_of_each_voxel() {
+        let t0 = std::time::Instant::now();
+        let at = |ms| t0 + std::time::Duration::from_millis(ms);
+        let points = [
+            Point3d::new_with_timestamp(0.1, 0.1, 0.1, 1.0, at(30)),
+            Point3d::new_with_timestamp(0.3, 0.5, 0.7, 3.0, at(10)),
+            Point3d::new_with_timestamp(1.2, 1.4, 1.6, 5.0, at(20)),
+        ];
+        let mut downsampled = downsample(&points, 1.0);
+        downsampled.sort_by(|a, b| a.position().x.total_cmp(&b.position().x));
+
+        assert_eq!(downsampled.len(), 2);
+        let centroid = &downsampled[0];
+        assert!((centroid.position() - na::Vector3::new(0.2, 0.3, 0.4)).norm() < 1e-6);
+        assert_eq!(centroid.intensity(), 2.0);
+        assert_eq!(centroid.timestamp(), at(30));
+        let single = &downsampled[1];
+        assert!((single.position() - points[2].position()).norm() < 1e-6);
+        assert_eq!(single.intensity(), 5.0);
+        assert_eq!(single.timestamp(), at(20));
+    }
+}